@@ -0,0 +1,367 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use serde_json::{json, Value};
+
+use crate::core::control::{self, TorrentStatus};
+use crate::core::tracker;
+use crate::file::config::Config;
+use crate::file::magnet::MagnetLink;
+use crate::file::resume;
+use crate::file::storage::Storage;
+use crate::file::torrent;
+
+pub const USAGE: &str = "\
+Usage: BaconNet [options] [command]
+
+Commands:
+  daemon                      Seed and download everything in the config (default)
+  seed <torrent>...           Seed only the given torrents
+  download <torrent>...       Download the given torrents or magnet links, then exit
+  create <path>               Create a torrent for a file or directory
+  verify <torrent> <path>     Check that path matches the torrent
+  info <torrent>              Show a torrent's details, magnet link and file tree
+  recheck <torrent>           Hash downloaded data and rebuild its resume state
+  tracker                     Run a tracker alongside everything daemon does
+  scrape <torrent>            Ask a torrent's trackers how big its swarm is
+
+Controlling a running node:
+  ctl list                    List its torrents
+  ctl add <torrent>           Start downloading a torrent or magnet link
+  ctl seed <torrent>          Start seeding a torrent
+  ctl pause <info_hash>       Stop requesting and sending pieces
+  ctl resume <info_hash>      Undo pause
+  ctl remove <info_hash>      Stop downloading or seeding a torrent
+
+Options:
+  --config <file>             Config file to use (default ./config.json)
+  --json                      Print results as JSON
+  --listen <address>          Address to listen on
+  --tcp-port <port>           TCP port to listen on
+  --udp-port <port>           UDP port to listen on
+  --dir <path>                Where download saves to and seed reads from
+  --peer <address>            Peer to list in a created torrent, can be repeated
+  --tracker <address>         Tracker to list in a created torrent, can be repeated
+  -h, --help                  Show this message";
+
+// Exit codes. Anything that ran but found a problem,
+// like a failed verify, exits with FAILURE.
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+
+pub enum Command {
+    Daemon,
+    Seed(Vec<String>),
+    Download(Vec<String>),
+    Create(String),
+    Verify(String, String),
+    Info(String),
+    Recheck(String),
+    Tracker,
+    Scrape(String),
+    // Method and argument for a running node
+    Ctl(Vec<String>),
+    Help,
+}
+
+pub struct Cli {
+    pub command: Command,
+    pub config: PathBuf,
+    pub json: bool,
+    // Flag and value pairs for NetworkConfig::apply_flags
+    pub network: Vec<String>,
+    pub dir: Option<String>,
+    pub peers: Vec<String>,
+    pub trackers: Vec<String>,
+}
+
+impl Cli {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut cli = Cli {
+            command: Command::Daemon,
+            config: PathBuf::from(crate::file::config::CONFIG_PATH),
+            json: false,
+            network: Vec::new(),
+            dir: None,
+            peers: Vec::new(),
+            trackers: Vec::new(),
+        };
+        let mut positional: Vec<String> = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or(format!("Missing value for {}", arg));
+
+            match arg.as_str() {
+                "-h" | "--help" => cli.command = Command::Help,
+                "--json" => cli.json = true,
+                "--config" => cli.config = PathBuf::from(value()?),
+                "--dir" => cli.dir = Some(value()?),
+                "--peer" => cli.peers.push(value()?),
+                "--tracker" => cli.trackers.push(value()?),
+                "--listen" | "--tcp-port" | "--udp-port" => {
+                    let value = value()?;
+                    cli.network.push(arg.clone());
+                    cli.network.push(value);
+                },
+                _ if arg.starts_with('-') => Err(format!("Unknown option {}", arg))?,
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        if matches!(cli.command, Command::Help) {
+            return Ok(cli);
+        }
+
+        let name = match positional.first() {
+            Some(n) => n.clone(),
+            None => return Ok(cli),
+        };
+        let rest: Vec<String> = positional.split_off(1);
+
+        cli.command = match (name.as_str(), rest.len()) {
+            ("daemon", 0) => Command::Daemon,
+            ("seed", n) if n > 0 => Command::Seed(rest),
+            ("download", n) if n > 0 => Command::Download(rest),
+            ("create", 1) => Command::Create(rest[0].clone()),
+            ("verify", 2) => Command::Verify(rest[0].clone(), rest[1].clone()),
+            ("info", 1) => Command::Info(rest[0].clone()),
+            ("recheck", 1) => Command::Recheck(rest[0].clone()),
+            ("tracker", 0) => Command::Tracker,
+            ("scrape", 1) => Command::Scrape(rest[0].clone()),
+            ("ctl", 1 | 2) => Command::Ctl(rest),
+            ("daemon" | "seed" | "download" | "create" | "verify" | "info" 
+                | "recheck" | "tracker" | "scrape" | "ctl", _) => {
+                Err(format!("Wrong number of arguments for {}", name))?
+            },
+            _ => Err(format!("Unknown command {}", name))?,
+        };
+
+        Ok(cli)
+    }
+}
+
+// Errors go to stderr either way, so scripts
+// reading stdout only ever see results
+fn fail(cli: &Cli, message: String) -> ExitCode {
+    if cli.json {
+        println!("{}", json!({ "error": message }));
+    }
+    eprintln!("{}", message);
+
+    ExitCode::from(EXIT_FAILURE)
+}
+
+pub fn create(cli: &Cli, path: &str) -> ExitCode {
+    let output = match torrent::create_torrent_file(path, cli.peers.clone(), cli.trackers.clone()) {
+        Ok(o) => o,
+        Err(e) => return fail(cli, format!("Failed to create torrent for {}: {}", path, e)),
+    };
+
+    if cli.json {
+        let info = match torrent::parse_torrent_file(&output) {
+            Ok(i) => i,
+            Err(e) => return fail(cli, format!("Failed to read back {}: {}", output, e)),
+        };
+        println!("{}", json!({
+            "torrent": output,
+            "info_hash": torrent::info_hash(&info),
+            "size": info.size,
+            "pieces": info.pieces.len(),
+        }));
+    }
+    else {
+        println!("Created {}", output);
+    }
+
+    ExitCode::SUCCESS
+}
+
+pub fn verify(cli: &Cli, torrent_file: &str, path: &str) -> ExitCode {
+    let info = match torrent::parse_torrent_file(torrent_file) {
+        Ok(i) => i,
+        Err(e) => return fail(cli, format!("Failed to read {}: {}", torrent_file, e)),
+    };
+
+    let valid = torrent::verify_file_tree(&info.files, path);
+    if cli.json {
+        println!("{}", json!({ "torrent": torrent_file, "path": path, "valid": valid }));
+    }
+    else {
+        println!("{}: {}", path, if valid { "OK" } else { "MISMATCH" });
+    }
+
+    match valid {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::from(EXIT_FAILURE),
+    }
+}
+
+pub fn info(cli: &Cli, torrent_file: &str) -> ExitCode {
+    let info = match torrent::parse_torrent_file(torrent_file) {
+        Ok(i) => i,
+        Err(e) => return fail(cli, format!("Failed to read {}: {}", torrent_file, e)),
+    };
+
+    if cli.json {
+        let files: Vec<serde_json::Value> = torrent::flatten_tree(&info.files)
+            .into_iter()
+            .map(|(path, size)| json!({ "path": path, "size": size }))
+            .collect();
+
+        println!("{}", json!({
+            "name": info.filename,
+            "info_hash": torrent::info_hash(&info),
+            "size": info.size,
+            "piece_length": info.piece_length,
+            "pieces": info.pieces.len(),
+            "created_on": info.created_on,
+            "peers": info.peers,
+            "trackers": info.trackers,
+            "magnet": MagnetLink::from_info(&info).to_string(),
+            "files": files,
+        }));
+    }
+    else {
+        println!("Name:       {}", info.filename);
+        println!("Info hash:  {}", torrent::info_hash(&info));
+        println!("Size:       {} bytes", info.size);
+        println!("Pieces:     {} x {} bytes", info.pieces.len(), info.piece_length);
+        println!("Created on: {}", info.created_on);
+        println!("Peers:      {}", info.peers.join(", "));
+        println!("Trackers:   {}", info.trackers.join(", "));
+        println!("Magnet:     {}", MagnetLink::from_info(&info));
+        println!();
+        torrent::print_tree(&info.files, 0);
+    }
+
+    ExitCode::SUCCESS
+}
+
+// Hash an existing download and rebuild its resume
+// state so only the missing pieces get downloaded
+pub fn recheck(cli: &Cli, config: &Config, torrent_file: &str) -> ExitCode {
+    let info = match torrent::parse_torrent_file(torrent_file) {
+        Ok(i) => i,
+        Err(e) => return fail(cli, format!("Failed to read {}: {}", torrent_file, e)),
+    };
+
+    // --dir wins, then the torrent's own download
+    // directory if the config lists it
+    let root = match (&cli.dir, config.downloads.iter().find(|e| e.torrent == torrent_file)) {
+        (Some(dir), _) => PathBuf::from(dir),
+        (None, Some(entry)) => config.download_dir(entry),
+        (None, None) => PathBuf::from(&config.download_dir),
+    };
+    let storage = match Storage::new(&root, &info) {
+        Ok(s) => s,
+        Err(e) => return fail(cli, format!("Invalid torrent {}: {}", torrent_file, e)),
+    };
+
+    let valid = match resume::recheck(&root, &info, &storage) {
+        Ok(v) => v,
+        Err(e) => return fail(cli, format!("Failed to recheck {}: {}", torrent_file, e)),
+    };
+
+    if cli.json {
+        println!("{}", json!({
+            "torrent": torrent_file,
+            "valid": valid.count(),
+            "pieces": valid.len(),
+        }));
+    }
+    else {
+        println!("{}: {}/{} pieces valid", info.filename, valid.count(), valid.len());
+    }
+
+    ExitCode::SUCCESS
+}
+
+// Ask every tracker in the torrent about its swarm.
+// Fails if none of them answer.
+pub async fn scrape(cli: &Cli, torrent_file: &str) -> ExitCode {
+    let info = match torrent::parse_torrent_file(torrent_file) {
+        Ok(i) => i,
+        Err(e) => return fail(cli, format!("Failed to read {}: {}", torrent_file, e)),
+    };
+    if info.trackers.is_empty() {
+        return fail(cli, format!("{} doesn't list any trackers", torrent_file));
+    }
+
+    let hash = torrent::info_hash(&info);
+    let mut results: Vec<Value> = Vec::new();
+    let mut answered = false;
+    for tracker in &info.trackers {
+        match tracker::scrape(tracker, &hash).await {
+            Ok(reply) => {
+                answered = true;
+                if !cli.json {
+                    println!("{}: {} seeds, {} others, downloaded {} times", tracker, 
+                        reply.complete, reply.incomplete, reply.downloaded);
+                }
+                results.push(json!({ "tracker": tracker, "result": reply }));
+            },
+            Err(e) => {
+                if !cli.json {
+                    println!("{}: {}", tracker, e);
+                }
+                results.push(json!({ "tracker": tracker, "error": e.to_string() }));
+            }
+        }
+    }
+
+    if cli.json {
+        println!("{}", json!({ "torrent": torrent_file, "info_hash": hash, "trackers": results }));
+    }
+
+    match answered {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::from(EXIT_FAILURE),
+    }
+}
+
+// Send a request to a node running with the same
+// config, through its control socket
+pub fn ctl(cli: &Cli, config: &Config, args: &[String]) -> ExitCode {
+    let arg = args.get(1);
+    let (method, params) = match (args[0].as_str(), arg) {
+        ("list", None) => ("list", Value::Null),
+        ("add", Some(torrent)) => ("add", json!({ "torrent": torrent, "download_dir": cli.dir })),
+        ("seed", Some(torrent)) => ("add", json!({ "torrent": torrent, "seed": true })),
+        ("pause" | "resume" | "remove", Some(hash)) => (args[0].as_str(), json!({ "info_hash": hash })),
+        _ => return usage_error(&format!("Invalid ctl command {}", args.join(" "))),
+    };
+
+    if !config.control.enabled {
+        return fail(cli, "Control socket is disabled in the config".to_string());
+    }
+    let result = match control::request(Path::new(&config.control.socket), method, params) {
+        Ok(r) => r,
+        Err(e) => return fail(cli, e),
+    };
+
+    if cli.json {
+        println!("{}", result);
+        return ExitCode::SUCCESS;
+    }
+
+    match method {
+        "list" => {
+            let statuses: Vec<TorrentStatus> = serde_json::from_value(result).unwrap_or_default();
+            for status in statuses {
+                let state = if status.paused { "paused" } else { "running" };
+                println!("{}  {:<8}  {:<7}  {}/{}  {}", status.info_hash, status.kind, 
+                    state, status.have, status.pieces, status.torrent);
+            }
+        },
+        "add" => println!("{}", result["info_hash"].as_str().unwrap_or_default()),
+        _ => println!("OK"),
+    }
+
+    ExitCode::SUCCESS
+}
+
+pub fn usage_error(message: &str) -> ExitCode {
+    eprintln!("{}\n\n{}", message, USAGE);
+    ExitCode::from(EXIT_USAGE)
+}
@@ -0,0 +1,70 @@
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::net::lookup_host;
+
+// Ports used when neither the config nor a
+// torrent's peer list says otherwise
+pub const DEFAULT_TCP_PORT: u16 = 8080;
+pub const DEFAULT_UDP_PORT: u16 = 8081;
+
+// Peers are always keyed by the address they listen on, written
+// the same way everywhere. On a dual stack socket IPv4 peers show
+// up as IPv4-mapped IPv6 addresses, so those are turned back into
+// plain IPv4 first.
+pub fn peer_addr(ip: IpAddr, port: u16) -> String {
+    SocketAddr::new(ip.to_canonical(), port).to_string()
+}
+
+// Turn an entry from a torrent's peer list into a peer address.
+// Entries can be a host name or an IPv4 or IPv6 address, with or
+// without a port (IPv6 with a port is written [addr]:port).
+// Entries without a port use the default one.
+pub async fn resolve_peer(peer: &str) -> Option<String> {
+    resolve_addr(peer, DEFAULT_TCP_PORT).await
+}
+
+// Same as resolve_peer, for addresses that
+// default to some other port
+pub async fn resolve_addr(peer: &str, default_port: u16) -> Option<String> {
+    let peer = peer.trim();
+    let bare = peer.trim_start_matches('[').trim_end_matches(']');
+
+    if let Ok(ip) = bare.parse::<IpAddr>() {
+        return Some(peer_addr(ip, default_port));
+    }
+
+    // Bare IPv6 addresses are handled above, so
+    // a colon here always comes before a port
+    let with_port = match peer.contains(':') {
+        true => peer.to_string(),
+        false => format!("{}:{}", peer, default_port),
+    };
+
+    let addr = lookup_host(with_port).await.ok()?.next()?;
+    Some(peer_addr(addr.ip(), addr.port()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn unmaps_ipv4_peers() {
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+
+        assert_eq!(peer_addr(mapped, 9000), "10.0.0.1:9000");
+        assert_eq!(peer_addr(IpAddr::V6(Ipv6Addr::LOCALHOST), 9000), "[::1]:9000");
+    }
+
+    #[tokio::test]
+    async fn resolves_peer_entries() {
+        assert_eq!(resolve_peer("10.0.0.1").await.unwrap(), format!("10.0.0.1:{}", DEFAULT_TCP_PORT));
+        assert_eq!(resolve_peer(" 10.0.0.1:9000 ").await.unwrap(), "10.0.0.1:9000");
+        assert_eq!(resolve_peer("::1").await.unwrap(), format!("[::1]:{}", DEFAULT_TCP_PORT));
+        assert_eq!(resolve_peer("[::1]").await.unwrap(), format!("[::1]:{}", DEFAULT_TCP_PORT));
+        assert_eq!(resolve_peer("[::1]:9000").await.unwrap(), "[::1]:9000");
+        assert_eq!(resolve_addr("10.0.0.1", DEFAULT_UDP_PORT).await.unwrap(),
+            format!("10.0.0.1:{}", DEFAULT_UDP_PORT));
+    }
+}
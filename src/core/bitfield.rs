@@ -0,0 +1,136 @@
+// Tracks which pieces of a torrent are held.
+// On the wire the bits are packed eight to a byte,
+// most significant bit first, with piece 0 in the
+// high bit of the first byte.
+#[derive(Clone, PartialEq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: u64,
+}
+
+impl Bitfield {
+    pub fn new(len: u64) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8) as usize],
+            len,
+        }
+    }
+
+    pub fn full(len: u64) -> Self {
+        let mut field = Self::new(len);
+        for i in 0..len {
+            field.set(i);
+        }
+
+        field
+    }
+
+    // Returns None if the byte count doesn't match the
+    // piece count or any padding bits are set
+    pub fn from_bytes(bytes: &[u8], len: u64) -> Option<Self> {
+        if bytes.len() as u64 != len.div_ceil(8) {
+            return None;
+        }
+
+        let field = Self {
+            bits: bytes.to_vec(),
+            len,
+        };
+
+        let padding = (field.bits.len() as u64 * 8) - len;
+        if padding > 0 {
+            let mask = (1u8 << padding) - 1;
+            if field.bits[field.bits.len() - 1] & mask != 0 {
+                return None;
+            }
+        }
+
+        Some(field)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.bits.clone()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn get(&self, index: u64) -> bool {
+        if index >= self.len {
+            return false;
+        }
+
+        self.bits[(index / 8) as usize] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: u64) {
+        if index < self.len {
+            self.bits[(index / 8) as usize] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.bits.iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn to_vec(&self) -> Vec<bool> {
+        (0..self.len).map(|i| self.get(i)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_high_bit_first() {
+        let mut field = Bitfield::new(10);
+        field.set(0);
+        field.set(9);
+
+        assert_eq!(field.to_bytes(), vec![0x80, 0x40]);
+        assert!(field.get(0) && field.get(9) && !field.get(1));
+        assert_eq!(field.count(), 2);
+    }
+
+    #[test]
+    fn ignores_out_of_range() {
+        let mut field = Bitfield::new(10);
+        field.set(10);
+        field.set(100);
+
+        assert!(field.is_empty());
+        assert!(!field.get(10));
+        assert_eq!(field.to_bytes(), vec![0, 0]);
+    }
+
+    #[test]
+    fn full_leaves_padding_clear() {
+        let field = Bitfield::full(10);
+
+        assert!(field.is_complete());
+        assert_eq!(field.to_bytes(), vec![0xff, 0xc0]);
+        assert!(Bitfield::from_bytes(&field.to_bytes(), 10) == Some(field));
+    }
+
+    #[test]
+    fn rejects_bad_bytes() {
+        // Padding bit set
+        assert!(Bitfield::from_bytes(&[0xff, 0xe0], 10).is_none());
+        // Wrong length
+        assert!(Bitfield::from_bytes(&[0xff], 10).is_none());
+        assert!(Bitfield::from_bytes(&[0xff, 0, 0], 10).is_none());
+        // No padding at all
+        assert!(Bitfield::from_bytes(&[0xff], 8).is_some());
+        assert!(Bitfield::from_bytes(&[], 0).is_some_and(|f| f.is_complete()));
+    }
+}
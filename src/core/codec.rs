@@ -0,0 +1,333 @@
+use std::io;
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::core::structs::{Packet, PacketType};
+use crate::core::transfer::Block;
+use crate::file::torrent::{from_hex, to_hex};
+
+// Wire format (all integers are big endian)
+//
+// +--------+---------+------+-----------+---------+
+// | length | version | type | info hash | payload |
+// |  u32   |   u8    |  u8  |    20     |  bytes  |
+// +--------+---------+------+-----------+---------+
+//
+// The length prefix counts every byte after itself,
+// so an empty payload still has a length of 22.
+// The info hash names the torrent the packet is about.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+pub const INFO_HASH_LEN: usize = 20;
+
+const LENGTH_LEN: usize = 4;
+const HEADER_LEN: usize = 1 + 1 + INFO_HASH_LEN;
+
+// Largest frame we are willing to buffer. A full piece
+// plus some room for whatever is wrapped around it.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+// Largest payload that fits in a single UDP datagram
+pub const MAX_DATAGRAM_LEN: usize = 65507;
+
+#[derive(Default, Clone, Copy)]
+pub struct PacketCodec;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Raw bytes of a hex encoded info hash. Anything that isn't
+// a valid hash becomes zeros, which no torrent will match.
+pub fn info_hash_bytes(hash: &str) -> [u8; INFO_HASH_LEN] {
+    let mut bytes = [0u8; INFO_HASH_LEN];
+    if let Some(raw) = from_hex(hash).filter(|h| h.len() == INFO_HASH_LEN) {
+        bytes.copy_from_slice(&raw);
+    }
+
+    bytes
+}
+
+impl Encoder<Packet> for PacketCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let length = HEADER_LEN + packet.content.len();
+        if length > MAX_FRAME_LEN {
+            return Err(invalid("Packet too large"));
+        }
+
+        dst.reserve(LENGTH_LEN + length);
+        dst.put_u32(length as u32);
+        dst.put_u8(PROTOCOL_VERSION);
+        dst.put_u8(packet.packet_type.into());
+        dst.put_slice(&info_hash_bytes(&packet.info_hash));
+        dst.put_slice(&packet.content);
+
+        Ok(())
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
+        // Wait until the length prefix has arrived
+        if src.len() < LENGTH_LEN {
+            return Ok(None);
+        }
+
+        let mut prefix = [0u8; LENGTH_LEN];
+        prefix.copy_from_slice(&src[..LENGTH_LEN]);
+        let length = u32::from_be_bytes(prefix) as usize;
+
+        if length < HEADER_LEN {
+            return Err(invalid("Frame shorter than header"));
+        }
+        if length > MAX_FRAME_LEN {
+            return Err(invalid("Frame too large"));
+        }
+
+        // Wait until the whole frame has arrived
+        if src.len() < LENGTH_LEN + length {
+            src.reserve(LENGTH_LEN + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LENGTH_LEN);
+        let version = src.get_u8();
+        if version != PROTOCOL_VERSION {
+            // Still consume the frame so the stream stays aligned
+            src.advance(length - 1);
+            return Err(invalid("Unsupported protocol version"));
+        }
+
+        let packet_type = PacketType::try_from(src.get_u8())
+            .map_err(|_| invalid("Unknown packet type"))?;
+        let info_hash = to_hex(&src.split_to(INFO_HASH_LEN));
+        let content = src.split_to(length - HEADER_LEN).to_vec();
+
+        Ok(Some(Packet {
+            packet_type,
+            dest_ip: String::new(),
+            from_ip: String::new(),
+            info_hash,
+            content,
+        }))
+    }
+}
+
+// Encode a packet into a single buffer suitable
+// for a UDP datagram
+pub fn encode_datagram(packet: Packet) -> Result<BytesMut, io::Error> {
+    let mut buf = BytesMut::new();
+    PacketCodec.encode(packet, &mut buf)?;
+
+    if buf.len() > MAX_DATAGRAM_LEN {
+        return Err(invalid("Packet too large for datagram"));
+    }
+
+    Ok(buf)
+}
+
+// Decode a packet from a single received UDP datagram
+pub fn decode_datagram(data: &[u8]) -> Result<Packet, io::Error> {
+    let mut buf = BytesMut::from(data);
+    match PacketCodec.decode(&mut buf)? {
+        Some(packet) => Ok(packet),
+        None => Err(invalid("Truncated datagram")),
+    }
+}
+
+// PieceDelivery payload is the piece index
+// followed by the raw piece bytes
+pub fn encode_piece(location: u64, data: &[u8]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::with_capacity(8 + data.len());
+    payload.extend_from_slice(&location.to_be_bytes());
+    payload.extend_from_slice(data);
+
+    payload
+}
+
+pub fn decode_piece(payload: &[u8]) -> Option<(u64, &[u8])> {
+    if payload.len() < 8 {
+        return None;
+    }
+
+    let (index, data) = payload.split_at(8);
+    let location = u64::from_be_bytes(index.try_into().ok()?);
+
+    Some((location, data))
+}
+
+// Block payload is the piece index, block index and
+// block count followed by the block bytes
+const BLOCK_HEADER_LEN: usize = 8 + 4 + 4;
+
+pub fn encode_block(block: &Block) -> Packet {
+    let mut payload: Vec<u8> = Vec::with_capacity(BLOCK_HEADER_LEN + block.data.len());
+    payload.extend_from_slice(&block.piece.to_be_bytes());
+    payload.extend_from_slice(&block.index.to_be_bytes());
+    payload.extend_from_slice(&block.count.to_be_bytes());
+    payload.extend_from_slice(&block.data);
+
+    Packet {
+        packet_type: PacketType::Block,
+        dest_ip: String::new(),
+        from_ip: String::new(),
+        info_hash: block.info_hash.clone(),
+        content: payload,
+    }
+}
+
+pub fn decode_block(packet: &Packet) -> Option<Block> {
+    let payload = &packet.content;
+    if payload.len() < BLOCK_HEADER_LEN {
+        return None;
+    }
+
+    let mut header = &payload[..BLOCK_HEADER_LEN];
+    let piece = header.get_u64();
+    let index = header.get_u32();
+    let count = header.get_u32();
+
+    Some(Block {
+        piece,
+        index,
+        count,
+        info_hash: packet.info_hash.clone(),
+        data: payload[BLOCK_HEADER_LEN..].to_vec(),
+    })
+}
+
+// BlockAck payload is the piece index and block index
+pub fn encode_ack(block: &Block) -> Packet {
+    let mut payload: Vec<u8> = Vec::with_capacity(12);
+    payload.extend_from_slice(&block.piece.to_be_bytes());
+    payload.extend_from_slice(&block.index.to_be_bytes());
+
+    Packet {
+        packet_type: PacketType::BlockAck,
+        dest_ip: String::new(),
+        from_ip: String::new(),
+        info_hash: block.info_hash.clone(),
+        content: payload,
+    }
+}
+
+pub fn decode_ack(payload: &[u8]) -> Option<(u64, u32)> {
+    if payload.len() != 12 {
+        return None;
+    }
+
+    let mut payload = payload;
+    Some((payload.get_u64(), payload.get_u32()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn packet(content: Vec<u8>) -> Packet {
+        Packet {
+            packet_type: PacketType::PieceRequest,
+            dest_ip: "10.0.0.1:8080".to_string(),
+            from_ip: String::new(),
+            info_hash: HASH.to_string(),
+            content,
+        }
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let mut buf = BytesMut::new();
+        PacketCodec.encode(packet(b"hello".to_vec()), &mut buf).unwrap();
+        PacketCodec.encode(packet(Vec::new()), &mut buf).unwrap();
+        assert_eq!(&buf[..LENGTH_LEN], &(HEADER_LEN as u32 + 5).to_be_bytes());
+
+        let first = PacketCodec.decode(&mut buf).unwrap().unwrap();
+        assert!(first.packet_type == PacketType::PieceRequest);
+        assert_eq!(first.info_hash, HASH);
+        assert_eq!(first.content, b"hello");
+        // Only used for local routing
+        assert!(first.dest_ip.is_empty());
+
+        let second = PacketCodec.decode(&mut buf).unwrap().unwrap();
+        assert!(second.content.is_empty());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_whole_frame() {
+        let mut full = BytesMut::new();
+        PacketCodec.encode(packet(vec![7; 100]), &mut full).unwrap();
+
+        let mut buf = BytesMut::from(&full[..2]);
+        assert!(PacketCodec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&full[2..50]);
+        assert!(PacketCodec.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(&full[50..]);
+        assert_eq!(PacketCodec.decode(&mut buf).unwrap().unwrap().content, vec![7; 100]);
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        let mut short = BytesMut::new();
+        short.put_u32(HEADER_LEN as u32 - 1);
+        assert!(PacketCodec.decode(&mut short).is_err());
+
+        let mut long = BytesMut::new();
+        long.put_u32(MAX_FRAME_LEN as u32 + 1);
+        assert!(PacketCodec.decode(&mut long).is_err());
+
+        let mut buf = BytesMut::new();
+        assert!(PacketCodec.encode(packet(vec![0; MAX_FRAME_LEN]), &mut buf).is_err());
+        assert!(encode_datagram(packet(vec![0; MAX_DATAGRAM_LEN])).is_err());
+        assert!(decode_datagram(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn skips_frames_of_other_versions() {
+        let mut buf = BytesMut::new();
+        PacketCodec.encode(packet(b"old".to_vec()), &mut buf).unwrap();
+        buf[LENGTH_LEN] = PROTOCOL_VERSION - 1;
+        PacketCodec.encode(packet(b"new".to_vec()), &mut buf).unwrap();
+
+        assert!(PacketCodec.decode(&mut buf).is_err());
+        assert_eq!(PacketCodec.decode(&mut buf).unwrap().unwrap().content, b"new");
+    }
+
+    #[test]
+    fn rejects_unknown_packet_type() {
+        let mut buf = BytesMut::new();
+        PacketCodec.encode(packet(Vec::new()), &mut buf).unwrap();
+        buf[LENGTH_LEN + 1] = u8::MAX;
+
+        assert!(PacketCodec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn round_trips_payloads() {
+        let data = vec![1, 2, 3];
+        assert_eq!(decode_piece(&encode_piece(9, &data)), Some((9, &data[..])));
+        assert!(decode_piece(&[0; 7]).is_none());
+
+        let block = Block {
+            piece: 4,
+            index: 2,
+            count: 3,
+            info_hash: HASH.to_string(),
+            data: data.clone(),
+        };
+        let decoded = decode_block(&encode_block(&block)).unwrap();
+        assert_eq!((decoded.piece, decoded.index, decoded.count), (4, 2, 3));
+        assert_eq!(decoded.data, data);
+
+        assert_eq!(decode_ack(&encode_ack(&block).content), Some((4, 2)));
+        assert!(decode_ack(&[0; 11]).is_none());
+    }
+}
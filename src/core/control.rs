@@ -0,0 +1,269 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::sync::CancellationToken;
+
+use crate::file::config::TorrentConfig;
+use crate::{info, warn};
+
+// Requests are a single line of JSON, this is plenty
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+// Error codes, the same ones JSON-RPC uses
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Anything that went wrong carrying out a valid request
+const REQUEST_FAILED: i64 = -32000;
+
+// What the control socket asks of the seed and download
+// supervisors. Every request is answered on its own channel.
+pub enum Control {
+    // Answers with the info hash of the new torrent
+    Add(TorrentConfig, oneshot::Sender<Result<String, String>>),
+    // The rest answer whether the torrent was found
+    Remove(String, oneshot::Sender<bool>),
+    Pause(String, oneshot::Sender<bool>),
+    Resume(String, oneshot::Sender<bool>),
+    List(oneshot::Sender<Vec<TorrentStatus>>),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub torrent: String,
+    // "download" or "seed"
+    pub kind: String,
+    pub paused: bool,
+    // Pieces we have out of the total
+    pub have: u64,
+    pub pieces: u64,
+}
+
+// One request per line:
+//   {"id": 1, "method": "pause", "params": {"info_hash": "..."}}
+// answered with one line:
+//   {"id": 1, "result": true}
+//   {"id": 1, "error": {"code": -32000, "message": "..."}}
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RequestError {
+    code: i64,
+    message: String,
+}
+
+impl RequestError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HashParams {
+    info_hash: String,
+}
+
+// Listen on the control socket until shutting down,
+// then remove it so the next run can take it over
+pub async fn serve(
+    path: &Path,
+    download: Sender<Control>,
+    seed: Sender<Control>,
+    shutdown: CancellationToken
+) {
+    let listener = match bind(path).await {
+        Ok(l) => l,
+        Err(e) => {
+            warn!("[CONTROL] Can't listen on {}: {}", path.display(), e);
+            return;
+        }
+    };
+    info!("[CONTROL] Listening on {}", path.display());
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((s, _)) => s,
+                Err(_) => continue,
+            },
+            _ = shutdown.cancelled() => break,
+        };
+
+        let download = download.clone();
+        let seed = seed.clone();
+        tokio::spawn(async move {
+            handle_client(stream, download, seed).await;
+        });
+    }
+
+    let _ = std::fs::remove_file(path);
+}
+
+// A socket file left behind by a node that didn't exit
+// cleanly is replaced, but not one a running node uses
+async fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another node is already using it"
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+}
+
+async fn handle_client(stream: UnixStream, download: Sender<Control>, seed: Sender<Control>) {
+    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_REQUEST_LEN));
+
+    while let Some(Ok(line)) = lines.next().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let result = handle_request(&request, &download, &seed).await;
+                respond(request.id, result)
+            },
+            Err(e) => respond(Value::Null, Err(RequestError::new(PARSE_ERROR, e.to_string()))),
+        };
+
+        if lines.send(response.to_string()).await.is_err() {
+            break;
+        }
+    }
+}
+
+fn respond(id: Value, result: Result<Value, RequestError>) -> Value {
+    match result {
+        Ok(result) => json!({ "id": id, "result": result }),
+        Err(e) => json!({ "id": id, "error": { "code": e.code, "message": e.message } }),
+    }
+}
+
+async fn handle_request(
+    request: &Request,
+    download: &Sender<Control>,
+    seed: &Sender<Control>
+) -> Result<Value, RequestError> {
+    match request.method.as_str() {
+        "list" => Ok(json!(list(download, seed).await)),
+        "add" => {
+            let (entry, seeding) = add_params(&request.params)?;
+            let supervisor = if seeding { seed } else { download };
+
+            let (reply, answer) = oneshot::channel();
+            let _ = supervisor.send(Control::Add(entry, reply)).await;
+            match answer.await {
+                Ok(Ok(hash)) => Ok(json!({ "info_hash": hash })),
+                Ok(Err(e)) => Err(RequestError::new(REQUEST_FAILED, e)),
+                Err(_) => Err(RequestError::new(REQUEST_FAILED, "Node is shutting down")),
+            }
+        },
+        "remove" | "pause" | "resume" => {
+            let params: HashParams = serde_json::from_value(request.params.clone())
+                .map_err(|e| RequestError::new(INVALID_PARAMS, e.to_string()))?;
+
+            // A download is seeded as it goes, so
+            // both sides may know the torrent
+            let mut found = false;
+            for supervisor in [download, seed] {
+                let (reply, answer) = oneshot::channel();
+                let hash = params.info_hash.clone();
+                let control = match request.method.as_str() {
+                    "remove" => Control::Remove(hash, reply),
+                    "pause" => Control::Pause(hash, reply),
+                    _ => Control::Resume(hash, reply),
+                };
+
+                let _ = supervisor.send(control).await;
+                found |= answer.await.unwrap_or(false);
+            }
+
+            match found {
+                true => Ok(json!(true)),
+                false => Err(RequestError::new(REQUEST_FAILED,
+                    format!("Unknown torrent {}", params.info_hash))),
+            }
+        },
+        method => Err(RequestError::new(METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+    }
+}
+
+// params are a torrent entry just like the ones in
+// the config, plus "seed": true to seed it instead
+fn add_params(params: &Value) -> Result<(TorrentConfig, bool), RequestError> {
+    let mut params = params.clone();
+    let seeding = match params.as_object_mut().and_then(|p| p.remove("seed")) {
+        None => false,
+        Some(Value::Bool(b)) => b,
+        Some(_) => return Err(RequestError::new(INVALID_PARAMS, "seed must be true or false")),
+    };
+
+    let entry: TorrentConfig = serde_json::from_value(params)
+        .map_err(|e| RequestError::new(INVALID_PARAMS, e.to_string()))?;
+    if entry.torrent.trim().is_empty() {
+        return Err(RequestError::new(INVALID_PARAMS, "torrent can't be empty"));
+    }
+
+    Ok((entry, seeding))
+}
+
+async fn list(download: &Sender<Control>, seed: &Sender<Control>) -> Vec<TorrentStatus> {
+    let mut statuses: Vec<TorrentStatus> = Vec::new();
+    for supervisor in [download, seed] {
+        let (reply, answer) = oneshot::channel();
+        let _ = supervisor.send(Control::List(reply)).await;
+
+        // Downloads being seeded as they go are
+        // only listed once, as downloads
+        for status in answer.await.unwrap_or_default() {
+            if !statuses.iter().any(|s| s.info_hash == status.info_hash) {
+                statuses.push(status);
+            }
+        }
+    }
+
+    statuses
+}
+
+// Send a single request to a running node and
+// wait for the answer. Used by the ctl command.
+pub fn request(path: &Path, method: &str, params: Value) -> Result<Value, String> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)
+        .map_err(|e| format!("Can't connect to {}: {}", path.display(), e))?;
+
+    let request = json!({ "id": 1, "method": method, "params": params });
+    writeln!(stream, "{}", request).map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| e.to_string())?;
+
+    let mut response: Value = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+    if let Some(error) = response.get("error") {
+        let message = error.get("message").and_then(|m| m.as_str()).unwrap_or("Unknown error");
+        return Err(message.to_string());
+    }
+
+    Ok(response["result"].take())
+}
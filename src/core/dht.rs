@@ -0,0 +1,775 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::core::address::{peer_addr, resolve_addr, DEFAULT_UDP_PORT};
+use crate::core::codec::encode_datagram;
+use crate::core::handshake::{peer_id, TorrentSet};
+use crate::core::structs::{Packet, PacketType};
+use crate::file::torrent::{from_hex, to_hex};
+use crate::{debug, info};
+
+// Kademlia style distributed hash table, so peers can be found
+// without a tracker or a peer list in the torrent. Node ids and
+// info hashes share the same 160 bit space, and the distance
+// between two of them is their XOR. Every node keeps a few
+// contacts at each distance from itself, and remembers who
+// announced the torrents whose hashes are close to its id.
+//
+// Messages go over the node's UDP socket with the usual framing,
+// as JSON. Every query has a transaction id its response echoes.
+
+const ID_LEN: usize = 20;
+type NodeId = [u8; ID_LEN];
+
+// Contacts per bucket, and how many nodes a lookup ends with
+const K: usize = 8;
+
+// Queries a lookup has in flight at once
+const ALPHA: usize = 3;
+
+// Upper bound on queries for a single lookup
+const MAX_LOOKUP_QUERIES: usize = 64;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A full bucket only makes room for a new contact by
+// dropping one that failed or went quiet this long ago
+const STALE_CONTACT: Duration = Duration::from_secs(15 * 60);
+
+// Contacts are dropped after failing this many queries in a row
+const MAX_FAILURES: u32 = 2;
+
+// Announced peers are forgotten after this long
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+// Most peers stored per torrent, and handed out per get_peers
+const MAX_PEERS: usize = 100;
+const MAX_REPLY_PEERS: usize = 50;
+
+// Most torrents peers are stored for
+const MAX_TORRENTS: usize = 1000;
+
+// How often our torrents are looked up and announced. A lookup
+// that found nobody is tried again sooner.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(30);
+
+// How often to check what needs doing
+const TICK: Duration = Duration::from_secs(15);
+
+// Tokens handed out by get_peers are good for
+// announce_peer until the secret changes twice
+const TOKEN_ROTATE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Query {
+    Ping,
+    FindNode { target: String },
+    GetPeers { info_hash: String },
+    // port is the TCP port the announcing peer listens on
+    AnnouncePeer { info_hash: String, port: u16, token: String },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct NodeInfo {
+    id: String,
+    // UDP address
+    addr: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Response {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    nodes: Vec<NodeInfo>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    peers: Vec<String>,
+    // Only from get_peers, needed to announce to that node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Body {
+    Query(Query),
+    Response(Response),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+    t: u64,
+    // Sender's node id, hex encoded
+    id: String,
+    body: Body,
+}
+
+fn parse_id(hex: &str) -> Option<NodeId> {
+    from_hex(hex)?.try_into().ok()
+}
+
+// Addresses from other nodes are only taken as an IP and
+// port, never a host name, and written the way we key them
+fn parse_addr(addr: &str) -> Option<String> {
+    let addr: SocketAddr = addr.parse().ok()?;
+    Some(peer_addr(addr.ip(), addr.port()))
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; ID_LEN];
+    for i in 0..ID_LEN {
+        d[i] = a[i] ^ b[i];
+    }
+
+    d
+}
+
+struct Contact {
+    id: NodeId,
+    addr: String,
+    last_seen: Instant,
+    failures: u32,
+}
+
+// Contacts sorted into buckets by how many leading bits
+// their id shares with ours. Each bucket is kept in order
+// of when its contacts were last heard from.
+struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Contact>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: (0..ID_LEN * 8).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own, id);
+        let zeros = d.iter()
+            .position(|b| *b != 0)
+            .map(|i| i * 8 + d[i].leading_zeros() as usize)?;
+
+        Some(zeros)
+    }
+
+    // We heard from this node
+    fn insert(&mut self, id: NodeId, addr: &str) {
+        let index = match self.bucket_index(&id) {
+            Some(i) => i,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+
+        let contact = match bucket.iter().position(|c| c.id == id) {
+            Some(pos) => bucket.remove(pos),
+            None => {
+                if bucket.len() >= K {
+                    let oldest = &bucket[0];
+                    if oldest.failures == 0 && oldest.last_seen.elapsed() < STALE_CONTACT {
+                        return;
+                    }
+                    bucket.remove(0);
+                }
+                Contact {
+                    id,
+                    addr: String::new(),
+                    last_seen: Instant::now(),
+                    failures: 0,
+                }
+            }
+        };
+
+        bucket.push(Contact {
+            addr: addr.to_string(),
+            last_seen: Instant::now(),
+            failures: 0,
+            ..contact
+        });
+    }
+
+    // A query to this address went unanswered
+    fn failed(&mut self, addr: &str) {
+        for bucket in self.buckets.iter_mut() {
+            for contact in bucket.iter_mut().filter(|c| c.addr == addr) {
+                contact.failures += 1;
+            }
+            bucket.retain(|c| c.failures < MAX_FAILURES);
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<(NodeId, String)> {
+        let mut contacts: Vec<(NodeId, String)> = self.buckets.iter()
+            .flatten()
+            .map(|c| (c.id, c.addr.clone()))
+            .collect();
+        contacts.sort_by_key(|(id, _)| distance(id, target));
+        contacts.truncate(count);
+
+        contacts
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+// Peers announced to us, keyed by info hash
+#[derive(Default)]
+struct PeerStore {
+    torrents: HashMap<String, HashMap<String, Instant>>,
+}
+
+impl PeerStore {
+    fn add(&mut self, info_hash: &NodeId, addr: String) {
+        let info_hash = to_hex(info_hash);
+
+        // Make room by forgetting the torrent nobody
+        // has announced for in the longest time
+        if self.torrents.len() >= MAX_TORRENTS && !self.torrents.contains_key(&info_hash) {
+            let oldest = self.torrents.iter()
+                .min_by_key(|(_, peers)| peers.values().max().copied())
+                .map(|(hash, _)| hash.clone());
+            if let Some(oldest) = oldest {
+                self.torrents.remove(&oldest);
+            }
+        }
+
+        let peers = self.torrents.entry(info_hash).or_default();
+
+        // Make room by forgetting whoever announced longest ago
+        if peers.len() >= MAX_PEERS && !peers.contains_key(&addr) {
+            let oldest = peers.iter().min_by_key(|(_, t)| **t).map(|(a, _)| a.clone());
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(addr, Instant::now());
+    }
+
+    fn get(&self, info_hash: &NodeId) -> Vec<String> {
+        let mut peers: Vec<String> = match self.torrents.get(&to_hex(info_hash)) {
+            Some(p) => p.keys().cloned().collect(),
+            None => Vec::new(),
+        };
+        peers.shuffle(&mut rand::rng());
+        peers.truncate(MAX_REPLY_PEERS);
+
+        peers
+    }
+
+    fn expire(&mut self) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, t| t.elapsed() < PEER_TTL);
+        }
+        self.torrents.retain(|_, p| !p.is_empty());
+    }
+}
+
+// Only nodes that asked for peers recently may announce,
+// so nobody can sign up addresses they don't own
+struct Tokens {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        Self {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn make(secret: &[u8; 16], addr: &SocketAddr) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(addr.ip().to_string().as_bytes());
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn issue(&self, addr: &SocketAddr) -> String {
+        Self::make(&self.current, addr)
+    }
+
+    fn check(&self, addr: &SocketAddr, token: &str) -> bool {
+        token == Self::make(&self.current, addr) || token == Self::make(&self.previous, addr)
+    }
+
+    fn rotate(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATE {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = Instant::now();
+        }
+    }
+}
+
+struct Candidate {
+    id: NodeId,
+    addr: String,
+    queried: bool,
+    token: Option<String>,
+    responded: bool,
+}
+
+pub struct Dht {
+    id: NodeId,
+    udp: Arc<UdpSocket>,
+    // TCP port we announce for our torrents
+    port: u16,
+    // Info hashes of everything we seed or download
+    torrents: TorrentSet,
+    // Peers found for our torrents go to the download side
+    found: Sender<Packet>,
+    table: Mutex<RoutingTable>,
+    peers: Mutex<PeerStore>,
+    tokens: Mutex<Tokens>,
+    // Queries waiting on a response, keyed by transaction
+    // id, along with the address they were sent to
+    pending: Mutex<HashMap<u64, (String, oneshot::Sender<Response>)>>,
+    next_t: AtomicU64,
+    // When each of our torrents is due to be announced next
+    due: Mutex<HashMap<String, Instant>>,
+}
+
+impl Dht {
+    pub fn new(
+        udp: Arc<UdpSocket>,
+        port: u16,
+        torrents: TorrentSet,
+        found: Sender<Packet>
+    ) -> Arc<Self> {
+        let id = peer_id();
+
+        Arc::new(Self {
+            id,
+            udp,
+            port,
+            torrents,
+            found,
+            table: Mutex::new(RoutingTable::new(id)),
+            peers: Mutex::new(PeerStore::default()),
+            tokens: Mutex::new(Tokens::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_t: AtomicU64::new(rand::random()),
+            due: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn send(&self, addr: &str, message: Message) {
+        let dest: SocketAddr = match addr.parse() {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        let content = match serde_json::to_vec(&message) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+        let packet = Packet {
+            packet_type: PacketType::Dht,
+            dest_ip: addr.to_string(),
+            from_ip: String::new(),
+            info_hash: String::new(),
+            content,
+        };
+
+        if let Ok(bytes) = encode_datagram(packet) {
+            let _ = self.udp.send_to(&bytes, dest).await;
+        }
+    }
+
+    // Send a query and wait for its response. Nodes that
+    // don't answer are eventually dropped from the table.
+    async fn query(&self, addr: &str, query: Query) -> Option<Response> {
+        let t = self.next_t.fetch_add(1, Ordering::Relaxed);
+        let (reply, answer) = oneshot::channel();
+        self.pending.lock().unwrap().insert(t, (addr.to_string(), reply));
+
+        self.send(addr, Message { t, id: to_hex(&self.id), body: Body::Query(query) }).await;
+        let result = timeout(QUERY_TIMEOUT, answer).await;
+        self.pending.lock().unwrap().remove(&t);
+
+        match result {
+            Ok(Ok(response)) => Some(response),
+            _ => {
+                self.table.lock().unwrap().failed(addr);
+                None
+            }
+        }
+    }
+
+    // Handle a message that arrived on the UDP socket
+    async fn handle_packet(&self, packet: Packet) {
+        let message: Message = match serde_json::from_slice(&packet.content) {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+        let from: SocketAddr = match packet.from_ip.parse() {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+        let id = match parse_id(&message.id) {
+            Some(i) => i,
+            None => return,
+        };
+
+        match message.body {
+            Body::Query(query) => {
+                // Anyone asking is a node we can ask too
+                self.table.lock().unwrap().insert(id, &packet.from_ip);
+
+                let body = self.answer(query, &from);
+                self.send(&packet.from_ip, Message { t: message.t, id: to_hex(&self.id), body }).await;
+            },
+            // Only accepted from the node the query went to.
+            // Errors just drop the waiting query.
+            response => {
+                let waiting = {
+                    let mut pending = self.pending.lock().unwrap();
+                    match pending.get(&message.t) {
+                        Some((addr, _)) if *addr == packet.from_ip => pending.remove(&message.t),
+                        _ => None,
+                    }
+                };
+
+                if let (Some((_, reply)), Body::Response(response)) = (waiting, response) {
+                    self.table.lock().unwrap().insert(id, &packet.from_ip);
+                    let _ = reply.send(response);
+                }
+            },
+        }
+    }
+
+    fn answer(&self, query: Query, from: &SocketAddr) -> Body {
+        let closest = |target: &NodeId| -> Vec<NodeInfo> {
+            self.table.lock().unwrap()
+                .closest(target, K)
+                .into_iter()
+                .map(|(id, addr)| NodeInfo { id: to_hex(&id), addr })
+                .collect()
+        };
+
+        match query {
+            Query::Ping => Body::Response(Response::default()),
+            Query::FindNode { target } => match parse_id(&target) {
+                Some(target) => Body::Response(Response {
+                    nodes: closest(&target),
+                    ..Default::default()
+                }),
+                None => Body::Error("Invalid target".to_string()),
+            },
+            Query::GetPeers { info_hash } => match parse_id(&info_hash) {
+                Some(target) => Body::Response(Response {
+                    nodes: closest(&target),
+                    peers: self.peers.lock().unwrap().get(&target),
+                    token: Some(self.tokens.lock().unwrap().issue(from)),
+                }),
+                None => Body::Error("Invalid info hash".to_string()),
+            },
+            Query::AnnouncePeer { info_hash, port, token } => {
+                if !self.tokens.lock().unwrap().check(from, &token) {
+                    return Body::Error("Invalid token".to_string());
+                }
+                let target = match parse_id(&info_hash) {
+                    Some(t) => t,
+                    None => return Body::Error("Invalid info hash".to_string()),
+                };
+                let info_hash = to_hex(&target);
+
+                // Peers are reached on the port they listen
+                // on, not the one the query came from
+                let addr = peer_addr(from.ip(), port);
+                self.peers.lock().unwrap().add(&target, addr.clone());
+
+                // No need to wait for our own lookup
+                // if it's a torrent we're downloading
+                if self.torrents.read().unwrap().contains(&info_hash) {
+                    self.report(&info_hash, vec![addr]);
+                }
+
+                Body::Response(Response::default())
+            },
+        }
+    }
+
+    // Hand peers for one of our torrents to the download side
+    fn report(&self, info_hash: &str, peers: Vec<String>) {
+        let content = match serde_json::to_vec(&peers) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        let _ = self.found.try_send(Packet {
+            packet_type: PacketType::PeersFound,
+            dest_ip: String::new(),
+            from_ip: String::new(),
+            info_hash: info_hash.to_string(),
+            content,
+        });
+    }
+
+    // Iterative lookup of the nodes closest to target, asking
+    // a few at a time until nobody closer turns up. For a
+    // get_peers lookup, peers they know about are collected too.
+    async fn lookup(&self, target: NodeId, get_peers: bool) -> (Vec<Candidate>, HashSet<String>) {
+        let mut candidates: Vec<Candidate> = self.table.lock().unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|(id, addr)| Candidate { id, addr, queried: false, token: None, responded: false })
+            .collect();
+        let mut peers: HashSet<String> = HashSet::new();
+        let query = match get_peers {
+            true => Query::GetPeers { info_hash: to_hex(&target) },
+            false => Query::FindNode { target: to_hex(&target) },
+        };
+
+        let mut queries = 0;
+        while queries < MAX_LOOKUP_QUERIES {
+            candidates.sort_by_key(|c| distance(&c.id, &target));
+
+            // Only the closest K matter
+            let batch: Vec<usize> = (0..candidates.len().min(K))
+                .filter(|i| !candidates[*i].queried)
+                .take(ALPHA)
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queries += batch.len();
+
+            let asked = batch.iter().map(|i| {
+                candidates[*i].queried = true;
+                let addr = candidates[*i].addr.clone();
+                let query = query.clone();
+                async move { self.query(&addr, query).await }
+            });
+            let responses: Vec<Option<Response>> = join_all(asked.collect::<Vec<_>>()).await;
+
+            for (i, response) in batch.into_iter().zip(responses) {
+                let response = match response {
+                    Some(r) => r,
+                    None => continue,
+                };
+                candidates[i].responded = true;
+                candidates[i].token = response.token;
+                peers.extend(response.peers.iter().filter_map(|p| parse_addr(p)));
+
+                for node in response.nodes {
+                    let id = match parse_id(&node.id) {
+                        Some(id) if id != self.id => id,
+                        _ => continue,
+                    };
+                    let addr = match parse_addr(&node.addr) {
+                        Some(a) => a,
+                        None => continue,
+                    };
+                    if !candidates.iter().any(|c| c.id == id) {
+                        candidates.push(Candidate {
+                            id,
+                            addr,
+                            queried: false,
+                            token: None,
+                            responded: false,
+                        });
+                    }
+                }
+            }
+        }
+
+        candidates.retain(|c| c.responded);
+        candidates.truncate(K);
+
+        (candidates, peers)
+    }
+
+    // Join the network through the given nodes, then
+    // look ourselves up to fill the table
+    async fn bootstrap(&self, nodes: &[String]) {
+        let pings = nodes.iter().map(|node| async move {
+            if let Some(addr) = resolve_addr(node, DEFAULT_UDP_PORT).await {
+                self.query(&addr, Query::Ping).await;
+            }
+        });
+        join_all(pings).await;
+
+        self.lookup(self.id, false).await;
+        info!("[DHT] Joined with {} contacts", self.table.lock().unwrap().len());
+    }
+
+    // Find peers for one of our torrents and
+    // announce ourselves to the closest nodes
+    async fn announce(&self, info_hash: &str) {
+        let target = match parse_id(info_hash) {
+            Some(t) => t,
+            None => return,
+        };
+
+        let (nodes, mut peers) = self.lookup(target, true).await;
+        peers.extend(self.peers.lock().unwrap().get(&target));
+
+        let announces = nodes.into_iter().filter_map(|node| {
+            let query = Query::AnnouncePeer {
+                info_hash: info_hash.to_string(),
+                port: self.port,
+                token: node.token?,
+            };
+            Some(async move { self.query(&node.addr, query).await })
+        });
+        join_all(announces.collect::<Vec<_>>()).await;
+
+        debug!("[DHT] Found {} peers for {}", peers.len(), info_hash);
+
+        // Try again soon if nobody turned up
+        let next = match peers.is_empty() {
+            true => ANNOUNCE_RETRY,
+            false => ANNOUNCE_INTERVAL,
+        };
+        self.due.lock().unwrap().insert(info_hash.to_string(), Instant::now() + next);
+
+        if !peers.is_empty() {
+            self.report(info_hash, peers.into_iter().collect());
+        }
+    }
+
+    // Torrents due to be announced, marked as taken care of
+    // for now. Nothing is due while we know no other nodes.
+    fn take_due(&self) -> Vec<String> {
+        if self.table.lock().unwrap().len() == 0 {
+            return Vec::new();
+        }
+
+        let torrents = self.torrents.read().unwrap().clone();
+        let mut due = self.due.lock().unwrap();
+        due.retain(|hash, _| torrents.contains(hash));
+
+        let now = Instant::now();
+        let mut ready: Vec<String> = Vec::new();
+        for hash in torrents {
+            if due.get(&hash).is_none_or(|t| *t <= now) {
+                due.insert(hash.clone(), now + ANNOUNCE_INTERVAL);
+                ready.push(hash);
+            }
+        }
+
+        ready
+    }
+}
+
+// Answer other nodes and keep our torrents announced until
+// shutting down. Lookups wait on responses that arrive through
+// this loop, so they run on tasks of their own.
+pub async fn run(
+    dht: Arc<Dht>,
+    receiver: &mut Receiver<Packet>,
+    bootstrap: Vec<String>,
+    shutdown: CancellationToken
+) {
+    let mut tick = tokio::time::interval(TICK);
+
+    loop {
+        tokio::select! {
+            Some(packet) = receiver.recv() => dht.handle_packet(packet).await,
+            _ = tick.tick() => {
+                dht.tokens.lock().unwrap().rotate();
+                dht.peers.lock().unwrap().expire();
+
+                // Keep trying to join while we know nobody
+                if dht.table.lock().unwrap().len() == 0 && !bootstrap.is_empty() {
+                    let dht = dht.clone();
+                    let bootstrap = bootstrap.clone();
+                    tokio::spawn(async move { dht.bootstrap(&bootstrap).await });
+                }
+
+                for hash in dht.take_due() {
+                    let dht = dht.clone();
+                    tokio::spawn(async move { dht.announce(&hash).await });
+                }
+            },
+            _ = shutdown.cancelled() => break,
+            else => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: usize) -> NodeId {
+        let mut id = [0; ID_LEN];
+        id[..8].copy_from_slice(&(n as u64).to_be_bytes());
+        id
+    }
+
+    #[test]
+    fn caps_peers_per_torrent() {
+        let mut store = PeerStore::default();
+        for port in 0..MAX_PEERS + 10 {
+            store.add(&hash(0), format!("10.0.0.1:{}", port));
+        }
+
+        let peers = &store.torrents[&to_hex(&hash(0))];
+        assert_eq!(peers.len(), MAX_PEERS);
+        assert!(!peers.contains_key("10.0.0.1:0"));
+        assert!(peers.contains_key(&format!("10.0.0.1:{}", MAX_PEERS + 9)));
+    }
+
+    #[test]
+    fn evicts_oldest_torrent() {
+        let mut store = PeerStore::default();
+        for n in 0..MAX_TORRENTS {
+            store.add(&hash(n), "10.0.0.1:8080".to_string());
+        }
+
+        // Everything but the second torrent was announced since
+        let later = Instant::now() + Duration::from_secs(1);
+        let second = to_hex(&hash(1));
+        for (key, peers) in store.torrents.iter_mut() {
+            if *key != second {
+                peers.values_mut().for_each(|t| *t = later);
+            }
+        }
+        store.add(&hash(MAX_TORRENTS), "10.0.0.1:8080".to_string());
+
+        assert_eq!(store.torrents.len(), MAX_TORRENTS);
+        assert!(store.torrents.contains_key(&to_hex(&hash(0))));
+        assert!(!store.torrents.contains_key(&to_hex(&hash(1))));
+        assert_eq!(store.get(&hash(MAX_TORRENTS)), vec!["10.0.0.1:8080"]);
+    }
+
+    #[test]
+    fn only_takes_info_hashes() {
+        assert!(parse_id("not a hash").is_none());
+        assert!(parse_id(&"ab".repeat(ID_LEN - 1)).is_none());
+        assert_eq!(parse_id(&"AB".repeat(ID_LEN)), Some([0xab; ID_LEN]));
+    }
+
+    #[test]
+    fn only_takes_socket_addresses() {
+        assert!(parse_addr("localhost:8081").is_none());
+        assert!(parse_addr("10.0.0.1").is_none());
+        assert_eq!(parse_addr("[::ffff:10.0.0.1]:8081").unwrap(), "10.0.0.1:8081");
+        assert_eq!(parse_addr("[::1]:8081").unwrap(), "[::1]:8081");
+    }
+}
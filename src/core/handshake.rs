@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::codec::Framed;
+
+use crate::core::codec::{info_hash_bytes, PacketCodec, INFO_HASH_LEN, PROTOCOL_VERSION};
+use crate::core::structs::{Packet, PacketType};
+use crate::error::{Error, Result};
+use crate::file::torrent::to_hex;
+
+// Both sides of every connection start by sending a
+// handshake, the side that connected going first.
+//
+// +---------+---------+------------+------+-----------+
+// | version | peer id | extensions | port | info hash |
+// |   u8    |   20    |    u64     | u16  |    20     |
+// +---------+---------+------------+------+-----------+
+//
+// port is the TCP port the sender listens on, since the
+// port a connection comes from says nothing about it.
+// Every later packet on the connection belongs to the
+// torrent named in the handshake. Peers on another protocol
+// version, asking for a torrent we don't have, or that
+// turn out to be ourselves are dropped.
+const PEER_ID_LEN: usize = 20;
+const HANDSHAKE_LEN: usize = 1 + PEER_ID_LEN + 8 + 2 + INFO_HASH_LEN;
+
+// How long to wait for the other side's handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Optional features, one bit each
+pub const EXT_UDP_BLOCKS: u64 = 1 << 0;
+pub const EXT_PEX: u64 = 1 << 1;
+pub const EXT_METADATA: u64 = 1 << 2;
+
+// Everything this build supports
+pub const EXTENSIONS: u64 = EXT_UDP_BLOCKS | EXT_PEX | EXT_METADATA;
+
+// Whether a peer with the given extensions understands
+// a packet type. Older peers drop the connection over
+// packet types they don't know.
+pub fn supports(extensions: u64, packet_type: &PacketType) -> bool {
+    match packet_type {
+        PacketType::Pex => extensions & EXT_PEX != 0,
+        PacketType::MetadataRequest | PacketType::MetadataReply => extensions & EXT_METADATA != 0,
+        _ => true,
+    }
+}
+
+// Info hashes of every torrent we seed or download
+pub type TorrentSet = Arc<RwLock<HashSet<String>>>;
+
+// Picked at random once per run
+pub fn peer_id() -> [u8; PEER_ID_LEN] {
+    static PEER_ID: OnceLock<[u8; PEER_ID_LEN]> = OnceLock::new();
+    *PEER_ID.get_or_init(rand::random)
+}
+
+pub struct Handshake {
+    pub version: u8,
+    pub peer_id: [u8; PEER_ID_LEN],
+    pub extensions: u64,
+    pub port: u16,
+    // Hex encoded, like everywhere else
+    pub info_hash: String,
+}
+
+impl Handshake {
+    pub fn new(info_hash: &str, port: u16) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            peer_id: peer_id(),
+            extensions: EXTENSIONS,
+            port,
+            info_hash: info_hash.to_string(),
+        }
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut content: Vec<u8> = Vec::with_capacity(HANDSHAKE_LEN);
+        content.push(self.version);
+        content.extend_from_slice(&self.peer_id);
+        content.extend_from_slice(&self.extensions.to_be_bytes());
+        content.extend_from_slice(&self.port.to_be_bytes());
+        content.extend_from_slice(&info_hash_bytes(&self.info_hash));
+
+        Packet {
+            packet_type: PacketType::Handshake,
+            dest_ip: String::new(),
+            from_ip: String::new(),
+            info_hash: self.info_hash.clone(),
+            content,
+        }
+    }
+
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        let content = &packet.content;
+        if packet.packet_type != PacketType::Handshake || content.len() != HANDSHAKE_LEN {
+            return None;
+        }
+
+        let mut peer_id = [0u8; PEER_ID_LEN];
+        peer_id.copy_from_slice(&content[1..1 + PEER_ID_LEN]);
+
+        let start = 1 + PEER_ID_LEN;
+        let extensions = u64::from_be_bytes(content[start..start + 8].try_into().ok()?);
+        let port = u16::from_be_bytes(content[start + 8..start + 10].try_into().ok()?);
+
+        Some(Self {
+            version: content[0],
+            peer_id,
+            extensions,
+            port,
+            info_hash: to_hex(&content[start + 10..]),
+        })
+    }
+}
+
+// Wait for the other side's handshake and make
+// sure it's someone we can talk to
+async fn receive(frames: &mut Framed<TcpStream, PacketCodec>) -> Result<Handshake> {
+    let packet = match timeout(HANDSHAKE_TIMEOUT, frames.next()).await {
+        Ok(Some(Ok(p))) => p,
+        Ok(Some(Err(e))) => return Err(e.into()),
+        _ => return Err(Error::Peer("No handshake from peer".to_string())),
+    };
+
+    let handshake = match Handshake::from_packet(&packet) {
+        Some(h) => h,
+        None => return Err(Error::Protocol("Expected a handshake".to_string())),
+    };
+
+    if handshake.version != PROTOCOL_VERSION {
+        return Err(Error::Protocol(format!("Unsupported protocol version {}", handshake.version)));
+    }
+    if handshake.peer_id == peer_id() {
+        return Err(Error::Peer("Connected to ourselves".to_string()));
+    }
+
+    Ok(handshake)
+}
+
+// Handshake on a connection we opened for the given
+// torrent. port is the TCP port we listen on.
+pub async fn connect(
+    frames: &mut Framed<TcpStream, PacketCodec>,
+    info_hash: &str,
+    port: u16
+) -> Result<Handshake> {
+    frames.send(Handshake::new(info_hash, port).to_packet()).await?;
+
+    let handshake = receive(frames).await?;
+    if handshake.info_hash != info_hash {
+        return Err(Error::Protocol("Peer answered for a different torrent".to_string()));
+    }
+
+    Ok(handshake)
+}
+
+// Handshake on a connection a peer opened. Only
+// answered if the torrent is one we know about.
+pub async fn accept(
+    frames: &mut Framed<TcpStream, PacketCodec>,
+    torrents: &TorrentSet,
+    port: u16
+) -> Result<Handshake> {
+    let handshake = receive(frames).await?;
+
+    let known = torrents.read().unwrap().contains(&handshake.info_hash);
+    if !known {
+        return Err(Error::Peer(format!("Unknown torrent {}", handshake.info_hash)));
+    }
+
+    frames.send(Handshake::new(&handshake.info_hash, port).to_packet()).await?;
+
+    Ok(handshake)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn round_trips() {
+        let sent = Handshake::new(HASH, 9000);
+        let received = Handshake::from_packet(&sent.to_packet()).unwrap();
+
+        assert_eq!(received.version, PROTOCOL_VERSION);
+        assert_eq!(received.peer_id, peer_id());
+        assert_eq!(received.extensions, EXTENSIONS);
+        assert_eq!(received.port, 9000);
+        assert_eq!(received.info_hash, HASH);
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        let mut packet = Handshake::new(HASH, 9000).to_packet();
+        packet.content.pop();
+        assert!(Handshake::from_packet(&packet).is_none());
+
+        let mut packet = Handshake::new(HASH, 9000).to_packet();
+        packet.packet_type = PacketType::FileCheck;
+        assert!(Handshake::from_packet(&packet).is_none());
+    }
+
+    #[test]
+    fn gates_extension_packets() {
+        assert!(supports(0, &PacketType::PieceRequest));
+        assert!(!supports(0, &PacketType::Pex));
+        assert!(!supports(EXT_PEX, &PacketType::MetadataRequest));
+        assert!(supports(EXTENSIONS, &PacketType::MetadataReply));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_talk_to_itself() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let torrents: TorrentSet = Arc::new(RwLock::new(HashSet::from([HASH.to_string()])));
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut frames = Framed::new(socket, PacketCodec);
+            accept(&mut frames, &torrents, addr.port()).await.is_err()
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut frames = Framed::new(socket, PacketCodec);
+        assert!(connect(&mut frames, HASH, 9000).await.is_err());
+        assert!(server.await.unwrap());
+    }
+}
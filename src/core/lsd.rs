@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Sender;
+use tokio_util::sync::CancellationToken;
+
+use crate::core::address::peer_addr;
+use crate::core::codec::{decode_datagram, encode_datagram, MAX_DATAGRAM_LEN};
+use crate::core::handshake::{peer_id, TorrentSet};
+use crate::core::structs::{Packet, PacketType};
+use crate::error::Result;
+use crate::file::torrent::to_hex;
+use crate::{debug, info, warn};
+
+// Local service discovery. Every node multicasts the info hashes
+// of its torrents on the LAN, and adds whoever announces one of
+// the same torrents to it as a peer. Hearing a new node announce
+// a torrent we have gets an answer straight away, so nobody has
+// to wait a whole interval to be found.
+//
+// Announcements are the usual framing, one datagram per torrent,
+// sent to a multicast group on a port every node shares.
+
+const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const LSD_PORT: u16 = 6772;
+
+// How often each torrent is announced
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Answers for a torrent go out at most this often,
+// however many nodes announce it
+const MIN_REPLY_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often to check for new torrents
+const TICK: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+struct Announcement {
+    // Hex encoded, so we can ignore our own
+    peer_id: String,
+    // TCP port the announcing node listens on
+    port: u16,
+    // Answering someone else's announcement
+    reply: bool,
+}
+
+// Shared with every other node on this machine, so
+// they all get to hear what's multicast on the LAN
+fn bind(interface: Ipv4Addr) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_PORT).into())?;
+
+    socket.join_multicast_v4(&LSD_GROUP, &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+struct Lsd {
+    udp: UdpSocket,
+    // TCP port we listen on
+    port: u16,
+    torrents: TorrentSet,
+    // Peers found go to the download side
+    found: Sender<Packet>,
+    // When each torrent is next announced
+    due: HashMap<String, Instant>,
+    // When each torrent was last answered for
+    replied: HashMap<String, Instant>,
+}
+
+impl Lsd {
+    async fn announce(&self, info_hash: &str, reply: bool) {
+        let announcement = Announcement {
+            peer_id: to_hex(&peer_id()),
+            port: self.port,
+            reply,
+        };
+        let content = match serde_json::to_vec(&announcement) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        let group = SocketAddr::from((LSD_GROUP, LSD_PORT));
+        let packet = Packet {
+            packet_type: PacketType::LocalAnnounce,
+            dest_ip: group.to_string(),
+            from_ip: String::new(),
+            info_hash: info_hash.to_string(),
+            content,
+        };
+        if let Ok(bytes) = encode_datagram(packet)
+            && let Err(e) = self.udp.send_to(&bytes, group).await
+        {
+            warn!("[LSD] Failed to announce {}: {}", info_hash, e);
+        }
+    }
+
+    // Announce every torrent that's new or due
+    async fn announce_due(&mut self) {
+        let torrents = self.torrents.read().unwrap().clone();
+        self.due.retain(|hash, _| torrents.contains(hash));
+        self.replied.retain(|_, t| t.elapsed() < MIN_REPLY_INTERVAL);
+
+        let now = Instant::now();
+        for hash in torrents {
+            if self.due.get(&hash).is_none_or(|t| *t <= now) {
+                self.due.insert(hash.clone(), now + ANNOUNCE_INTERVAL);
+                self.announce(&hash, false).await;
+            }
+        }
+    }
+
+    async fn handle_datagram(&mut self, bytes: &[u8], from: SocketAddr) {
+        let packet = match decode_datagram(bytes) {
+            Ok(p) if p.packet_type == PacketType::LocalAnnounce => p,
+            _ => return,
+        };
+        let announcement: Announcement = match serde_json::from_slice(&packet.content) {
+            Ok(a) => a,
+            Err(_) => return,
+        };
+
+        let ours = self.torrents.read().unwrap().contains(&packet.info_hash);
+        if !ours || announcement.peer_id == to_hex(&peer_id()) {
+            return;
+        }
+
+        let addr = peer_addr(from.ip(), announcement.port);
+        debug!("[LSD] Found {} for {}", addr, packet.info_hash);
+
+        if let Ok(content) = serde_json::to_vec(&vec![addr]) {
+            let _ = self.found.try_send(Packet {
+                packet_type: PacketType::PeersFound,
+                dest_ip: String::new(),
+                from_ip: String::new(),
+                info_hash: packet.info_hash.clone(),
+                content,
+            });
+        }
+
+        // Let the new node know about us too. The answer is
+        // multicast, so it does for anyone else asking as well.
+        let recent = self.replied.get(&packet.info_hash)
+            .is_some_and(|t| t.elapsed() < MIN_REPLY_INTERVAL);
+        if !announcement.reply && !recent {
+            self.replied.insert(packet.info_hash.clone(), Instant::now());
+            self.announce(&packet.info_hash, true).await;
+        }
+    }
+}
+
+// Announce our torrents on the LAN and listen for
+// everyone else's until shutting down. Not being able
+// to join the group only turns discovery off.
+pub async fn run(
+    interface: Ipv4Addr,
+    port: u16,
+    torrents: TorrentSet,
+    found: Sender<Packet>,
+    shutdown: CancellationToken
+) {
+    let udp = match bind(interface) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("[LSD] Couldn't join {}:{}, local discovery is off: {}", LSD_GROUP, LSD_PORT, e);
+            return;
+        }
+    };
+    info!("[LSD] Discovering peers on {}:{}", LSD_GROUP, LSD_PORT);
+
+    let mut lsd = Lsd {
+        udp,
+        port,
+        torrents,
+        found,
+        due: HashMap::new(),
+        replied: HashMap::new(),
+    };
+    let mut tick = tokio::time::interval(TICK);
+    let mut buf: Vec<u8> = vec![0; MAX_DATAGRAM_LEN];
+
+    loop {
+        tokio::select! {
+            result = lsd.udp.recv_from(&mut buf) => {
+                if let Ok((len, from)) = result {
+                    lsd.handle_datagram(&buf[..len], from).await;
+                }
+            },
+            _ = tick.tick() => lsd.announce_due().await,
+            _ = shutdown.cancelled() => break,
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::{timeout_at, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::core::address::resolve_peer;
+use crate::core::structs::{Packet, PacketType, TorrentInfo};
+use crate::error::{Error, Result};
+use crate::file::magnet::MagnetLink;
+use crate::file::torrent::{info_hash, parse_torrent};
+use crate::{debug, info, warn};
+
+// Metadata exchange. A magnet link only has the info hash,
+// so the rest of the torrent is asked for from peers that
+// have it, a chunk at a time. Whatever comes back is only
+// used if it hashes to the info hash we asked for.
+//
+// Requests are the chunk index. Replies are the index, the
+// size of the whole torrent and the chunk itself. A peer
+// that can't help answers with FileDeny.
+
+const METADATA_CHUNK: usize = 64 * 1024;
+
+// Bigger torrents than this aren't believed
+const MAX_METADATA_LEN: u64 = 16 * 1024 * 1024;
+
+// How long a peer gets to send each chunk
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long to wait for new peers once every known one
+// failed, before trying them all again
+const PEER_WAIT: Duration = Duration::from_secs(30);
+
+const INDEX_LEN: usize = 4;
+const TOTAL_LEN: usize = 8;
+
+pub fn decode_request(content: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(content.try_into().ok()?))
+}
+
+// A single chunk of a torrent's metadata,
+// or None if there's no such chunk
+pub fn encode_chunk(metadata: &[u8], index: u32) -> Option<Vec<u8>> {
+    let start = index as usize * METADATA_CHUNK;
+    if start >= metadata.len() {
+        return None;
+    }
+    let end = (start + METADATA_CHUNK).min(metadata.len());
+
+    let mut content: Vec<u8> = Vec::with_capacity(INDEX_LEN + TOTAL_LEN + end - start);
+    content.extend_from_slice(&index.to_be_bytes());
+    content.extend_from_slice(&(metadata.len() as u64).to_be_bytes());
+    content.extend_from_slice(&metadata[start..end]);
+
+    Some(content)
+}
+
+fn decode_chunk(content: &[u8]) -> Option<(u32, u64, &[u8])> {
+    let index = u32::from_be_bytes(content.get(..INDEX_LEN)?.try_into().ok()?);
+    let total = u64::from_be_bytes(content.get(INDEX_LEN..INDEX_LEN + TOTAL_LEN)?.try_into().ok()?);
+
+    Some((index, total, &content[INDEX_LEN + TOTAL_LEN..]))
+}
+
+struct MetadataFetch<'a> {
+    info_hash: String,
+    // Peers not asked yet
+    candidates: VecDeque<String>,
+    tried: HashSet<String>,
+    sender: &'a Sender<Packet>,
+    receiver: &'a mut Receiver<Packet>,
+    shutdown: &'a CancellationToken,
+}
+
+impl MetadataFetch<'_> {
+    // Peers found by the DHT, the LAN or trackers
+    // turn up while we wait on something else
+    fn add_found(&mut self, packet: &Packet) {
+        if packet.packet_type != PacketType::PeersFound {
+            return;
+        }
+
+        if let Ok(peers) = serde_json::from_slice::<Vec<String>>(&packet.content) {
+            for peer in peers {
+                if !self.tried.contains(&peer) && !self.candidates.contains(&peer) {
+                    self.candidates.push_back(peer);
+                }
+            }
+        }
+    }
+
+    async fn next_packet(&mut self, deadline: Instant) -> Result<Option<Packet>> {
+        tokio::select! {
+            result = timeout_at(deadline, self.receiver.recv()) => match result {
+                Ok(Some(packet)) => Ok(Some(packet)),
+                Ok(None) => Err(Error::Peer("Node is shutting down".to_string())),
+                Err(_) => Ok(None),
+            },
+            _ = self.shutdown.cancelled() => {
+                Err(Error::Peer("Stopped before the torrent was fetched".to_string()))
+            },
+        }
+    }
+
+    async fn request(&mut self, peer: &str, index: u32) -> Result<(u64, Vec<u8>)> {
+        self.sender.send(Packet {
+            packet_type: PacketType::MetadataRequest,
+            dest_ip: peer.to_string(),
+            from_ip: String::new(),
+            info_hash: self.info_hash.clone(),
+            content: index.to_be_bytes().to_vec(),
+        }).await?;
+
+        let deadline = Instant::now() + CHUNK_TIMEOUT;
+        loop {
+            let packet = match self.next_packet(deadline).await? {
+                Some(p) => p,
+                None => return Err(Error::Peer("Timed out".to_string())),
+            };
+
+            if packet.from_ip != peer {
+                self.add_found(&packet);
+                continue;
+            }
+
+            match packet.packet_type {
+                PacketType::MetadataReply => {
+                    if let Some((i, total, data)) = decode_chunk(&packet.content) && i == index {
+                        return Ok((total, data.to_vec()));
+                    }
+                },
+                PacketType::FileDeny => return Err(Error::Peer("Doesn't have it".to_string())),
+                PacketType::PeerUnreachable => return Err(Error::Peer("Unreachable".to_string())),
+                _ => (),
+            }
+        }
+    }
+
+    // Every chunk from a single peer, in order
+    async fn fetch_from(&mut self, peer: &str) -> Result<TorrentInfo> {
+        let mut metadata: Vec<u8> = Vec::new();
+        let mut expected: Option<u64> = None;
+
+        for index in 0.. {
+            let (total, data) = self.request(peer, index).await?;
+
+            // The size can't change halfway through, and every
+            // chunk but the last one has to be a full one
+            let total = *expected.get_or_insert(total);
+            let left = total.saturating_sub(metadata.len() as u64);
+            if total == 0
+                || total > MAX_METADATA_LEN
+                || data.len() as u64 != left.min(METADATA_CHUNK as u64)
+            {
+                return Err(Error::Protocol("Bad metadata chunk".to_string()));
+            }
+
+            metadata.extend_from_slice(&data);
+            if metadata.len() as u64 == total {
+                break;
+            }
+        }
+
+        let info = parse_torrent(&metadata)?;
+        if info_hash(&info) != self.info_hash {
+            return Err(Error::Integrity("Metadata doesn't match the info hash".to_string()));
+        }
+
+        Ok(info)
+    }
+}
+
+// Fetch the torrent a magnet link stands for. Peers in the link
+// are asked first, then any found while waiting. Only gives up
+// when shutting down.
+pub async fn fetch(
+    magnet: &MagnetLink,
+    sender: &Sender<Packet>,
+    receiver: &mut Receiver<Packet>,
+    shutdown: &CancellationToken
+) -> Result<TorrentInfo> {
+    let mut candidates: VecDeque<String> = VecDeque::new();
+    for peer in &magnet.peers {
+        match resolve_peer(peer).await {
+            Some(addr) => candidates.push_back(addr),
+            None => warn!("[METADATA] Couldn't resolve peer {}", peer),
+        }
+    }
+
+    let mut fetch = MetadataFetch {
+        info_hash: magnet.info_hash.clone(),
+        candidates,
+        tried: HashSet::new(),
+        sender,
+        receiver,
+        shutdown,
+    };
+    info!("[METADATA] Fetching {}", magnet.display_name());
+
+    loop {
+        let peer = match fetch.candidates.pop_front() {
+            Some(p) => p,
+            None => {
+                // Nobody left to ask, so wait to hear of someone
+                // new, or try everyone again after a while
+                match fetch.next_packet(Instant::now() + PEER_WAIT).await? {
+                    Some(packet) => fetch.add_found(&packet),
+                    None => {
+                        let tried: Vec<String> = fetch.tried.drain().collect();
+                        fetch.candidates.extend(tried);
+                    },
+                }
+                continue;
+            }
+        };
+        fetch.tried.insert(peer.clone());
+
+        match fetch.fetch_from(&peer).await {
+            Ok(info) => {
+                info!("[METADATA] Got {} from {}", info.filename, peer);
+                return Ok(keep_verified(info, magnet, &peer));
+            },
+            // Only shutting down ends the fetch
+            Err(e) if fetch.shutdown.is_cancelled() => return Err(e),
+            Err(e) => debug!("[METADATA] Couldn't fetch {} from {}: {}", magnet.info_hash, peer, e),
+        }
+    }
+}
+
+// Only what the info hash covers can be trusted, and the rest
+// would be saved along with it. Peers and trackers come from the
+// link instead, plus the peer that sent the torrent.
+fn keep_verified(mut info: TorrentInfo, magnet: &MagnetLink, source: &str) -> TorrentInfo {
+    info.peers = magnet.peers.clone();
+    if !info.peers.iter().any(|p| p == source) {
+        info.peers.push(source.to_string());
+    }
+    info.trackers = magnet.trackers.clone();
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_round_trip() {
+        let metadata: Vec<u8> = (0..METADATA_CHUNK + 10).map(|i| i as u8).collect();
+
+        let first = encode_chunk(&metadata, 0).unwrap();
+        let (index, total, data) = decode_chunk(&first).unwrap();
+        assert_eq!((index, total), (0, metadata.len() as u64));
+        assert_eq!(data, &metadata[..METADATA_CHUNK]);
+
+        let last = encode_chunk(&metadata, 1).unwrap();
+        assert_eq!(decode_chunk(&last).unwrap().2, &metadata[METADATA_CHUNK..]);
+
+        assert!(encode_chunk(&metadata, 2).is_none());
+        assert!(decode_chunk(&[0; INDEX_LEN]).is_none());
+    }
+
+    #[test]
+    fn drops_unverified_fields() {
+        let info: TorrentInfo = serde_json::from_value(serde_json::json!({
+            "filename": "dir",
+            "created_on": "0",
+            "size": 1,
+            "peers": ["6.6.6.6:8080"],
+            "pieces": ["00"],
+            "trackers": ["6.6.6.6:6969"],
+            "files": {
+                "filename": "dir",
+                "file_type": "directory",
+                "size": 1,
+                "hash": "bad",
+                "children": [{ "filename": "a", "file_type": "file", "size": 1, "hash": "bad" }]
+            }
+        })).unwrap();
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:baconnet:0123456789abcdef0123456789abcdef01234567&tr=10.0.0.1:6969&x.pe=10.0.0.2:8080"
+        ).unwrap();
+
+        let hash = info_hash(&info);
+        let info = keep_verified(info, &magnet, "10.0.0.3:8080");
+
+        assert_eq!(info_hash(&info), hash);
+        assert_eq!(info.peers, vec!["10.0.0.2:8080", "10.0.0.3:8080"]);
+        assert_eq!(info.trackers, vec!["10.0.0.1:6969"]);
+    }
+}
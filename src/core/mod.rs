@@ -1,3 +1,17 @@
 pub mod structs;
+pub mod codec;
+pub mod bitfield;
+pub mod picker;
+pub mod transfer;
+pub mod rate;
+pub mod address;
+pub mod pool;
+pub mod control;
+pub mod dht;
+pub mod handshake;
+pub mod lsd;
+pub mod metadata;
+pub mod pex;
 pub mod receive;
 pub mod send;
+pub mod tracker;
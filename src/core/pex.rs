@@ -0,0 +1,130 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::structs::{Packet, PacketType};
+use crate::debug;
+
+// Peer exchange. Peers connected for a torrent tell each other
+// every so often which peers they've found or lost since the
+// last time, so a swarm can find itself without a tracker. Only
+// sent to peers whose handshake says they understand it (EXT_PEX).
+
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+// Most peers listed in a single message
+const MAX_ADDED: usize = 50;
+const MAX_DROPPED: usize = 50;
+
+// Messages from a peer that come in closer together than
+// this are ignored, so nobody can flood us with addresses
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Default, Serialize, Deserialize)]
+struct PexMessage {
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    dropped: Vec<String>,
+}
+
+pub struct Pex {
+    // What each peer has been told about so far
+    sent: HashMap<String, HashSet<String>>,
+    // When each peer last sent us a message
+    received: HashMap<String, Instant>,
+    next: Instant,
+}
+
+impl Pex {
+    pub fn new() -> Self {
+        Self {
+            sent: HashMap::new(),
+            received: HashMap::new(),
+            next: Instant::now() + PEX_INTERVAL,
+        }
+    }
+
+    pub fn next(&self) -> Instant {
+        self.next
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.next
+    }
+
+    // A message for every peer, listing what changed since the
+    // last one it got. Peers with nothing new get nothing.
+    pub fn messages(&mut self, info_hash: &str, peers: &[String]) -> Vec<Packet> {
+        self.next = Instant::now() + PEX_INTERVAL;
+        self.sent.retain(|peer, _| peers.contains(peer));
+        self.received.retain(|_, t| t.elapsed() < MIN_RECEIVE_INTERVAL);
+
+        let mut packets: Vec<Packet> = Vec::new();
+        for peer in peers {
+            let current: HashSet<&String> = peers.iter().filter(|p| *p != peer).collect();
+            let sent = self.sent.entry(peer.clone()).or_default();
+
+            let message = PexMessage {
+                added: current.iter()
+                    .filter(|p| !sent.contains(**p))
+                    .take(MAX_ADDED)
+                    .map(|p| p.to_string())
+                    .collect(),
+                dropped: sent.iter()
+                    .filter(|p| !current.contains(p))
+                    .take(MAX_DROPPED)
+                    .cloned()
+                    .collect(),
+            };
+            if message.added.is_empty() && message.dropped.is_empty() {
+                continue;
+            }
+
+            sent.extend(message.added.iter().cloned());
+            for dropped in &message.dropped {
+                sent.remove(dropped);
+            }
+
+            let content = match serde_json::to_vec(&message) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            packets.push(Packet {
+                packet_type: PacketType::Pex,
+                dest_ip: peer.clone(),
+                from_ip: String::new(),
+                info_hash: info_hash.to_string(),
+                content,
+            });
+        }
+
+        packets
+    }
+
+    // Peers from a message worth trying. Dropped peers only
+    // cancel out ones added in the same message, whether a
+    // peer is still around is something we find out ourselves.
+    pub fn receive(&mut self, packet: &Packet) -> Vec<String> {
+        if let Some(last) = self.received.get(&packet.from_ip)
+            && last.elapsed() < MIN_RECEIVE_INTERVAL
+        {
+            return Vec::new();
+        }
+        self.received.insert(packet.from_ip.clone(), Instant::now());
+
+        let message: PexMessage = match serde_json::from_slice(&packet.content) {
+            Ok(m) => m,
+            Err(_) => return Vec::new(),
+        };
+
+        debug!("[PEX] {} added {} peers and dropped {}",
+            packet.from_ip, message.added.len(), message.dropped.len());
+
+        message.added.into_iter()
+            .filter(|p| !message.dropped.contains(p) && *p != packet.from_ip)
+            .take(MAX_ADDED)
+            .collect()
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Pieces a single peer may be asked for at once
+pub const MAX_OUTSTANDING: usize = 4;
+
+// How long to wait for a piece before asking someone else
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct PeerState {
+    pieces: Vec<bool>,
+    outstanding: usize,
+}
+
+struct Pending {
+    peer: String,
+    requested_at: Instant,
+}
+
+// Decides which piece to ask which peer for.
+// Pieces held by the fewest peers are requested first
+// so that rare pieces don't disappear from the swarm.
+pub struct PiecePicker {
+    have: Vec<bool>,
+    availability: Vec<u32>,
+    peers: HashMap<String, PeerState>,
+    pending: HashMap<u64, Pending>,
+    // Last peer that failed to deliver each piece
+    failed: HashMap<u64, String>,
+    max_outstanding: usize,
+    timeout: Duration,
+}
+
+impl PiecePicker {
+    pub fn new(piece_count: u64, max_outstanding: usize, timeout: Duration) -> Self {
+        Self {
+            have: vec![false; piece_count as usize],
+            availability: vec![0; piece_count as usize],
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+            failed: HashMap::new(),
+            max_outstanding,
+            timeout,
+        }
+    }
+
+    // Register a peer along with the pieces it holds.
+    // Re-adding a peer replaces what we knew about it.
+    pub fn add_peer(&mut self, peer: &str, pieces: Vec<bool>) {
+        self.remove_peer(peer);
+
+        for (i, has) in pieces.iter().enumerate() {
+            if *has && i < self.availability.len() {
+                self.availability[i] += 1;
+            }
+        }
+
+        self.peers.insert(peer.to_string(), PeerState {
+            pieces,
+            outstanding: 0,
+        });
+    }
+
+    // Forget a peer. Anything it was asked for
+    // becomes available to request again.
+    pub fn remove_peer(&mut self, peer: &str) {
+        let state = match self.peers.remove(peer) {
+            Some(s) => s,
+            None => return,
+        };
+
+        for (i, has) in state.pieces.iter().enumerate() {
+            if *has && i < self.availability.len() {
+                self.availability[i] -= 1;
+            }
+        }
+
+        self.pending.retain(|_, p| p.peer != peer);
+    }
+
+    // A peer has told us it now has another piece
+    pub fn peer_has(&mut self, peer: &str, piece: u64) {
+        let index = piece as usize;
+        if index >= self.availability.len() {
+            return;
+        }
+
+        if let Some(state) = self.peers.get_mut(peer) {
+            if state.pieces.len() < self.have.len() {
+                state.pieces.resize(self.have.len(), false);
+            }
+            if !state.pieces[index] {
+                state.pieces[index] = true;
+                self.availability[index] += 1;
+            }
+        }
+    }
+
+    pub fn peer_list(&self) -> Vec<String> {
+        self.peers.keys().cloned().collect()
+    }
+
+    // Pick as many new requests as the peers have room for,
+    // rarest pieces first. Returned requests are marked pending.
+    pub fn next_requests(&mut self) -> Vec<(String, u64)> {
+        let mut wanted: Vec<u64> = (0..self.have.len() as u64)
+            .filter(|i| !self.have[*i as usize] && !self.pending.contains_key(i))
+            .filter(|i| self.availability[*i as usize] > 0)
+            .collect();
+        wanted.sort_by_key(|i| (self.availability[*i as usize], *i));
+
+        let mut requests: Vec<(String, u64)> = Vec::new();
+        for piece in wanted {
+            let peer = match self.choose_peer(piece) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            if let Some(state) = self.peers.get_mut(&peer) {
+                state.outstanding += 1;
+            }
+            self.pending.insert(piece, Pending {
+                peer: peer.clone(),
+                requested_at: Instant::now(),
+            });
+            requests.push((peer, piece));
+        }
+
+        requests
+    }
+
+    // Least busy peer with the piece and room for another request.
+    // The peer that last failed this piece is only used as a last resort.
+    fn choose_peer(&self, piece: u64) -> Option<String> {
+        let failed = self.failed.get(&piece);
+
+        self.peers
+            .iter()
+            .filter(|(_, s)| s.outstanding < self.max_outstanding)
+            .filter(|(_, s)| s.pieces.get(piece as usize).copied().unwrap_or(false))
+            .min_by_key(|(peer, s)| (Some(*peer) == failed, s.outstanding, (*peer).clone()))
+            .map(|(peer, _)| peer.clone())
+    }
+
+    fn release(&mut self, piece: u64) -> Option<String> {
+        let pending = self.pending.remove(&piece)?;
+        if let Some(state) = self.peers.get_mut(&pending.peer) {
+            state.outstanding = state.outstanding.saturating_sub(1);
+        }
+
+        Some(pending.peer)
+    }
+
+    // Piece arrived and passed verification
+    pub fn piece_received(&mut self, piece: u64) {
+        if piece as usize >= self.have.len() {
+            return;
+        }
+
+        self.release(piece);
+        self.failed.remove(&piece);
+        self.have[piece as usize] = true;
+    }
+
+    // Piece arrived but was bad. It will be
+    // requested again, preferably from someone else.
+    pub fn piece_failed(&mut self, piece: u64) {
+        if let Some(peer) = self.release(piece) {
+            self.failed.insert(piece, peer);
+        }
+    }
+
+    // Release every request that has been waiting too long
+    // and return the pieces so they can be reported
+    pub fn expire(&mut self) -> Vec<u64> {
+        let expired: Vec<u64> = self.pending
+            .iter()
+            .filter(|(_, p)| p.requested_at.elapsed() >= self.timeout)
+            .map(|(piece, _)| *piece)
+            .collect();
+
+        for piece in &expired {
+            self.piece_failed(*piece);
+        }
+
+        expired
+    }
+
+    // Whether any requested piece hasn't arrived yet
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.iter().all(|h| *h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_rarest_first() {
+        let mut picker = PiecePicker::new(3, 1, REQUEST_TIMEOUT);
+        picker.add_peer("a", vec![true, true, true]);
+        picker.add_peer("b", vec![true, false, true]);
+        picker.add_peer("c", vec![false, false, true]);
+
+        // Piece 1 only a has, piece 0 a and b, piece 2 everyone
+        let requests = picker.next_requests();
+        assert_eq!(requests, vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 0),
+            ("c".to_string(), 2),
+        ]);
+        assert!(picker.next_requests().is_empty());
+    }
+
+    #[test]
+    fn counts_have_towards_rarity() {
+        let mut picker = PiecePicker::new(2, MAX_OUTSTANDING, REQUEST_TIMEOUT);
+        picker.add_peer("a", vec![true, false]);
+        picker.add_peer("b", vec![true, false]);
+        picker.peer_has("a", 1);
+
+        assert_eq!(picker.next_requests(), vec![("a".to_string(), 1), ("b".to_string(), 0)]);
+    }
+
+    #[test]
+    fn retries_elsewhere_after_failure() {
+        let mut picker = PiecePicker::new(1, MAX_OUTSTANDING, REQUEST_TIMEOUT);
+        picker.add_peer("a", vec![true]);
+        picker.add_peer("b", vec![true]);
+
+        assert_eq!(picker.next_requests(), vec![("a".to_string(), 0)]);
+        picker.piece_failed(0);
+        assert_eq!(picker.next_requests(), vec![("b".to_string(), 0)]);
+
+        picker.piece_received(0);
+        assert!(picker.is_complete());
+        assert!(!picker.has_pending());
+    }
+
+    #[test]
+    fn expires_slow_requests() {
+        let mut picker = PiecePicker::new(1, MAX_OUTSTANDING, Duration::ZERO);
+        picker.add_peer("a", vec![true]);
+        picker.next_requests();
+
+        assert_eq!(picker.expire(), vec![0]);
+        assert!(!picker.has_pending());
+        assert_eq!(picker.next_requests(), vec![("a".to_string(), 0)]);
+    }
+
+    #[test]
+    fn removed_peer_frees_its_pieces() {
+        let mut picker = PiecePicker::new(1, MAX_OUTSTANDING, REQUEST_TIMEOUT);
+        picker.add_peer("a", vec![true]);
+        picker.next_requests();
+        picker.remove_peer("a");
+
+        assert!(!picker.has_pending());
+        assert!(picker.next_requests().is_empty());
+        picker.add_peer("b", vec![true]);
+        assert_eq!(picker.next_requests(), vec![("b".to_string(), 0)]);
+    }
+}
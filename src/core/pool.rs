@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Framed;
+
+use crate::core::codec::PacketCodec;
+use crate::core::handshake;
+use crate::core::structs::{Packet, PacketType};
+use crate::error::Result;
+use crate::{info, warn};
+
+// Packets queued for a single peer
+const PEER_CHANNEL_LIMIT: usize = 64;
+
+// How long a single connection attempt may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Attempts per connect, with a doubling delay in between
+const CONNECT_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+// After a peer couldn't be reached, packets for it are
+// failed straight away for a while. The wait doubles
+// every time the peer is still down.
+const BASE_COOLDOWN: Duration = Duration::from_secs(5);
+const MAX_COOLDOWN: Duration = Duration::from_secs(300);
+
+// Connections with no traffic for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// How long closing the pool waits for queued packets
+// to be written and connections to be shut down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Queues of every open connection, by peer address and info
+// hash. Shared with tcp_in, so packets for a peer that connected
+// to us go back over that connection instead of a new one.
+pub type PeerQueues = Arc<Mutex<HashMap<(String, String), Sender<Packet>>>>;
+
+// Keeps one connection open per peer and torrent, and
+// reuses it for every packet sent to that peer about
+// that torrent
+pub struct ConnectionPool {
+    peers: PeerQueues,
+    tasks: Vec<JoinHandle<()>>,
+    inbound: Sender<Packet>,
+    // TCP port we listen on, for the handshake
+    port: u16,
+}
+
+impl ConnectionPool {
+    // Anything peers send back over our connections, along
+    // with PeerUnreachable notices, goes to inbound
+    pub fn new(inbound: Sender<Packet>, port: u16, peers: PeerQueues) -> Self {
+        Self {
+            peers,
+            tasks: Vec::new(),
+            inbound,
+            port,
+        }
+    }
+
+    // Never waits on a peer. A peer that can't keep up has
+    // its packets failed rather than holding up everyone else.
+    pub fn send(&mut self, packet: Packet) {
+        let key = (packet.dest_ip.clone(), packet.info_hash.clone());
+        let mut peers = self.peers.lock().unwrap();
+
+        // Reuse the peer's connection if its task is still around
+        let packet = match peers.get(&key) {
+            Some(peer) => match peer.try_send(packet) {
+                Ok(()) => return,
+                Err(TrySendError::Full(packet)) => {
+                    warn!("[TCP] Too much queued for {}, dropping a packet", key.0);
+                    let _ = self.inbound.try_send(unreachable(&key.0, &key.1, &packet));
+                    return;
+                },
+                Err(TrySendError::Closed(packet)) => packet,
+            },
+            None => packet,
+        };
+
+        let (sender, receiver) = channel(PEER_CHANNEL_LIMIT);
+        let connection = PeerConnection {
+            addr: key.0.clone(),
+            info_hash: key.1.clone(),
+            inbound: self.inbound.clone(),
+            port: self.port,
+            failures: 0,
+            down_until: None,
+            extensions: 0,
+        };
+        let task = tokio::spawn(async move {
+            connection.run(receiver).await
+        });
+        self.tasks.retain(|t| !t.is_finished());
+        self.tasks.push(task);
+
+        // A fresh channel can't be closed or full yet
+        let _ = sender.try_send(packet);
+        peers.insert(key, sender);
+    }
+
+    // Send whatever is still queued and shut every
+    // connection down cleanly rather than just dropping it
+    pub async fn close(mut self) {
+        self.peers.lock().unwrap().clear();
+
+        let tasks = std::mem::take(&mut self.tasks);
+        let _ = timeout(CLOSE_TIMEOUT, futures::future::join_all(tasks)).await;
+    }
+}
+
+// The writing half of a connection a peer opened to us
+pub struct InboundConnection {
+    key: (String, String),
+    queue: Sender<Packet>,
+    writer: JoinHandle<()>,
+    peers: PeerQueues,
+}
+
+impl InboundConnection {
+    // Anything sent to the peer about the torrent goes over this
+    // connection from now on. It's known to work, so it takes the
+    // place of one of our own.
+    pub fn adopt(
+        peers: &PeerQueues,
+        addr: String,
+        info_hash: String,
+        frames: SplitSink<Framed<TcpStream, PacketCodec>, Packet>,
+        extensions: u64
+    ) -> Self {
+        let (queue, outgoing) = channel(PEER_CHANNEL_LIMIT);
+        let writer = tokio::spawn(write_frames(frames, outgoing, extensions));
+
+        let key = (addr, info_hash);
+        peers.lock().unwrap().insert(key.clone(), queue.clone());
+
+        Self {
+            key,
+            queue,
+            writer,
+            peers: peers.clone(),
+        }
+    }
+
+    // Send whatever is still queued and hang up. Packets for
+    // the peer open a new connection after this.
+    pub async fn close(self) {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if peers.get(&self.key).is_some_and(|q| q.same_channel(&self.queue)) {
+                peers.remove(&self.key);
+            }
+        }
+        drop(self.queue);
+
+        let mut writer = self.writer;
+        if timeout(CLOSE_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+        }
+    }
+}
+
+// Write queued packets until the queue is dropped or the peer goes
+// away. Dropping the queue here lets the pool know it's gone.
+async fn write_frames(
+    mut frames: SplitSink<Framed<TcpStream, PacketCodec>, Packet>,
+    mut outgoing: Receiver<Packet>,
+    extensions: u64
+) {
+    while let Some(packet) = outgoing.recv().await {
+        // Left out rather than breaking the connection
+        if !handshake::supports(extensions, &packet.packet_type) {
+            continue;
+        }
+
+        if frames.send(packet).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = frames.close().await;
+}
+
+// Forward every packet read from a connection
+async fn read_frames(
+    mut frames: SplitStream<Framed<TcpStream, PacketCodec>>,
+    addr: String,
+    info_hash: String,
+    inbound: Sender<Packet>
+) {
+    while let Some(frame) = frames.next().await {
+        let mut packet: Packet = match frame {
+            Ok(p) => p,
+            Err(_) => break,
+        };
+
+        // Only we get to say a peer is unreachable, the
+        // handshake is already done, and the connection
+        // is only about the torrent it was opened for
+        if packet.packet_type == PacketType::PeerUnreachable
+            || packet.packet_type == PacketType::Handshake
+            || packet.info_hash != info_hash
+        {
+            continue;
+        }
+
+        packet.from_ip = addr.clone();
+        if inbound.send(packet).await.is_err() {
+            break;
+        }
+    }
+}
+
+struct PeerConnection {
+    addr: String,
+    info_hash: String,
+    inbound: Sender<Packet>,
+    port: u16,
+    failures: u32,
+    down_until: Option<Instant>,
+    // What the peer said it supports in its last handshake
+    extensions: u64,
+}
+
+impl PeerConnection {
+    async fn run(mut self, mut outgoing: Receiver<Packet>) {
+        let mut pending: Option<Packet> = None;
+
+        loop {
+            let retrying = pending.is_some();
+            let packet = match pending.take() {
+                Some(p) => p,
+                None => match timeout(IDLE_TIMEOUT, outgoing.recv()).await {
+                    Ok(Some(p)) => p,
+                    _ => return,
+                },
+            };
+
+            // Still backing off from the last failure
+            if let Some(until) = self.down_until && Instant::now() < until {
+                self.report(&packet).await;
+                continue;
+            }
+
+            let frames = match self.connect().await {
+                Some(f) => f,
+                None => {
+                    // Fail everything already queued as well
+                    // so nobody waits on a dead peer
+                    self.report(&packet).await;
+                    while let Ok(queued) = outgoing.try_recv() {
+                        self.report(&queued).await;
+                    }
+                    continue;
+                }
+            };
+
+            // A packet that couldn't be written is tried
+            // once more on a new connection
+            pending = self.serve(frames, packet, &mut outgoing).await;
+            if retrying && let Some(unsent) = pending.take() {
+                self.report(&unsent).await;
+            }
+        }
+    }
+
+    // Connect and handshake. A peer that refuses the
+    // handshake is treated the same as one that's down.
+    async fn connect(&mut self) -> Option<Framed<TcpStream, PacketCodec>> {
+        let mut delay = RETRY_DELAY;
+
+        for attempt in 0..CONNECT_ATTEMPTS {
+            if attempt > 0 {
+                sleep(delay).await;
+                delay *= 2;
+            }
+
+            let stream = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.addr)).await {
+                Ok(Ok(s)) => s,
+                _ => continue,
+            };
+
+            let mut frames = Framed::new(stream, PacketCodec);
+            match handshake::connect(&mut frames, &self.info_hash, self.port).await {
+                Ok(handshake) => {
+                    self.extensions = handshake.extensions;
+                    self.failures = 0;
+                    self.down_until = None;
+                    return Some(frames);
+                },
+                Err(e) => {
+                    warn!("[TCP] Handshake with {} failed: {}", self.addr, e);
+                    break;
+                }
+            }
+        }
+
+        let cooldown = BASE_COOLDOWN
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_COOLDOWN);
+        self.failures += 1;
+        self.down_until = Some(Instant::now() + cooldown);
+
+        info!("[TCP] {} unreachable, backing off for {}s", self.addr, cooldown.as_secs());
+        None
+    }
+
+    // Write packets to the connection until it breaks or goes idle.
+    // Returns the packet that couldn't be sent, if any.
+    async fn serve(
+        &mut self,
+        frames: Framed<TcpStream, PacketCodec>,
+        first: Packet,
+        outgoing: &mut Receiver<Packet>
+    ) -> Option<Packet> {
+        let (mut frames, read) = frames.split();
+        let reader: JoinHandle<()> = tokio::spawn(read_frames(
+            read,
+            self.addr.clone(),
+            self.info_hash.clone(),
+            self.inbound.clone()
+        ));
+
+        let mut next = Some(first);
+        let unsent = loop {
+            let packet = match next.take() {
+                Some(p) => p,
+                None => match timeout(IDLE_TIMEOUT, outgoing.recv()).await {
+                    Ok(Some(p)) => p,
+                    _ => break None,
+                },
+            };
+
+            // Peer closed its end, so writing would just be lost
+            if reader.is_finished() {
+                break Some(packet);
+            }
+
+            // Left out rather than breaking the connection
+            if !handshake::supports(self.extensions, &packet.packet_type) {
+                continue;
+            }
+
+            if frames.send(packet.clone()).await.is_err() {
+                break Some(packet);
+            }
+        };
+
+        // Lets the peer know we're done rather
+        // than leaving it to time out
+        let _ = frames.close().await;
+        reader.abort();
+        unsent
+    }
+
+    // Let the thread that sent the packet know it didn't get through
+    async fn report(&self, packet: &Packet) {
+        let _ = self.inbound.send(unreachable(&self.addr, &self.info_hash, packet)).await;
+    }
+}
+
+// Hand a packet to another task without waiting. Threads, the
+// manager and the connections all pass packets to each other,
+// so waiting on a full channel could leave two of them waiting
+// on each other for good. A packet that doesn't fit is dropped,
+// like one for a peer that can't keep up. Returns whether it went.
+pub fn forward(sender: &Sender<Packet>, packet: Packet) -> Result<bool> {
+    match sender.try_send(packet) {
+        Ok(()) => Ok(true),
+        Err(TrySendError::Full(packet)) => {
+            warn!("[MAIN] Too much queued for {}, dropping a packet", packet.info_hash);
+            Ok(false)
+        },
+        Err(TrySendError::Closed(packet)) => Err(SendError(packet).into()),
+    }
+}
+
+fn unreachable(addr: &str, info_hash: &str, packet: &Packet) -> Packet {
+    Packet {
+        packet_type: PacketType::PeerUnreachable,
+        dest_ip: String::new(),
+        from_ip: addr.to_string(),
+        info_hash: info_hash.to_string(),
+        content: vec![packet.packet_type.clone().into()],
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// Token bucket, refilled at a fixed number of bytes per second
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        // Never save up more than a second's worth
+        let elapsed = self.last.elapsed().as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = Instant::now();
+    }
+}
+
+// A rate limit that can be shared between threads and
+// combined with others, say a global limit and one for a
+// single torrent. Taking bytes is allowed to go into debt
+// so a whole piece can go through a small limit; the debt
+// is paid off before anything else is allowed.
+#[derive(Clone, Default)]
+pub struct RateLimit {
+    buckets: Vec<Arc<Mutex<Bucket>>>,
+}
+
+impl RateLimit {
+    // 0 means unlimited
+    pub fn new(rate: u64) -> Self {
+        if rate == 0 {
+            return Self::default();
+        }
+
+        let bucket = Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        };
+
+        Self {
+            buckets: vec![Arc::new(Mutex::new(bucket))],
+        }
+    }
+
+    // Limited by both this and other
+    pub fn and(&self, other: &RateLimit) -> Self {
+        let mut buckets = self.buckets.clone();
+        buckets.extend(other.buckets.iter().cloned());
+
+        Self { buckets }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.buckets.iter().all(|bucket| {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.refill();
+            bucket.tokens > 0.0
+        })
+    }
+
+    pub fn take(&self, bytes: u64) {
+        for bucket in &self.buckets {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.refill();
+            bucket.tokens -= bytes as f64;
+        }
+    }
+
+    pub fn try_take(&self, bytes: u64) -> bool {
+        if !self.is_available() {
+            return false;
+        }
+
+        self.take(bytes);
+        true
+    }
+}
@@ -1,178 +1,478 @@
-use std::fs::OpenOptions;
-use std::io::{Write, Seek, SeekFrom};
-use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     Packet, PacketType, TorrentInfo, 
 };
 
-use super::structs::PieceRequest;
+use super::structs::{PieceRequest, SHUTDOWN_GRACE};
+use super::address::resolve_peer;
+use super::bitfield::Bitfield;
+use super::rate::RateLimit;
+use super::send::PartialSeed;
+use super::codec::{decode_block, decode_piece, encode_ack, encode_datagram};
+use super::transfer::{block_count, PieceAssembler, Transport};
+use super::picker::{PiecePicker, MAX_OUTSTANDING, REQUEST_TIMEOUT};
+use super::tracker::{Announcer, Event, TransferStats};
+use super::pex::Pex;
+use super::pool::forward;
+use crate::file::torrent::{hash_piece, info_hash, parse_torrent_file};
+use crate::file::storage::Storage;
+use crate::file::resume::{load_resume, save_resume};
+use crate::error::{Error, Result};
+use crate::{debug, error, info, warn};
+
+// How often to check for timed out requests
+const TICK: Duration = Duration::from_secs(1);
+
+// How we ask peers to deliver pieces
+const TRANSPORT: Transport = Transport::Udp;
 
 pub struct DownloadThread {
-    id: u64,
-    info: TorrentInfo
+    info: TorrentInfo,
+    hash: String,
+    storage: Storage,
+    picker: PiecePicker,
+    have: Arc<RwLock<Bitfield>>,
+    assembler: PieceAssembler,
+    // Port of the socket blocks arrive on
+    udp_port: u16,
+    // Torrent contents are recreated under this directory
+    root: PathBuf,
+    max_peers: usize,
+    download: RateLimit,
+    // Set from the control socket. Nothing new is
+    // requested while paused.
+    paused: Arc<AtomicBool>,
+    // TCP port we listen on, for trackers
+    port: u16,
+    stats: Arc<TransferStats>,
+    announcer: Announcer,
+    pex: Pex,
 }
 
 impl DownloadThread {
-    pub fn new(id: u64, filename: &str) -> Result<Self, Box<dyn Error>> {
-        let file = std::fs::read_to_string(filename)?;
-        let info: TorrentInfo = serde_json::from_str(file.as_str())?;
+    pub fn new(
+        filename: &str,
+        root: &Path,
+        max_peers: usize,
+        download: RateLimit,
+        port: u16
+    ) -> Result<Self> {
+        let info: TorrentInfo = parse_torrent_file(filename)?;
+
+        let storage = Storage::new(root, &info)?;
 
+        // Pick up where a previous run left off
+        // so only the missing pieces get requested
+        let have = load_resume(root, &info, &storage);
+        let mut picker = PiecePicker::new(info.piece_count(), MAX_OUTSTANDING, REQUEST_TIMEOUT);
+        for location in 0..info.piece_count() {
+            if have.get(location) {
+                picker.piece_received(location);
+            }
+        }
+        if !have.is_empty() {
+            info!("[DOWNLOAD] Resuming {} with {}/{} pieces", 
+                info.filename, have.count(), info.piece_count());
+        }
+
+        let hash = info_hash(&info);
         Ok(Self {
-            id,
+            announcer: Announcer::new(&info.trackers, &hash, port),
+            hash,
             info,
+            storage,
+            picker,
+            have: Arc::new(RwLock::new(have)),
+            assembler: PieceAssembler::new(),
+            udp_port: 0,
+            root: root.to_path_buf(),
+            max_peers,
+            download,
+            paused: Arc::new(AtomicBool::new(false)),
+            port,
+            stats: Arc::new(TransferStats::default()),
+            pex: Pex::new(),
         })
     }
 
-    // Ping list of peers stated in file info
-    // to ensure they are active and have
-    // the correct file
-    pub async fn notify_peers(
-        &mut self,
-        sender: &mpsc::Sender<Packet>,
-        receiver: &mut mpsc::Receiver<Packet>
-    ) -> Vec<String> {
-        // Collect active peers
-        let mut active: Vec<String> = Vec::new();
+    pub fn info_hash(&self) -> &str {
+        &self.hash
+    }
+
+    // Take over the state a supervisor already handed out,
+    // for downloads that had to fetch their torrent first
+    pub fn share_state(&mut self, have: &Arc<RwLock<Bitfield>>, paused: &Arc<AtomicBool>) {
+        *have.write().unwrap() = self.have.read().unwrap().clone();
+        self.have = have.clone();
+        self.paused = paused.clone();
+    }
+
+    // Share the pieces we have so far with
+    // the seed side so other peers can get them
+    pub fn partial_seed(&self, upload: RateLimit) -> PartialSeed {
+        PartialSeed {
+            info: self.info.clone(),
+            root: self.root.clone(),
+            have: self.have.clone(),
+            upload,
+            port: self.port,
+            stats: self.stats.clone(),
+        }
+    }
+
+    // Ping list of peers stated in file info to find out
+    // which are active and which pieces each of them has.
+    // Answers come in as Bitfields and are handled in
+    // receive, so a dead peer never holds up the rest.
+    pub async fn notify_peers(&self, sender: &mpsc::Sender<Packet>) -> Result<()> {
+        let mut peers: Vec<String> = Vec::new();
         for peer in &self.info.peers {
-            let mut addr: String = peer.clone();
-            addr.push_str(format!(":{}", crate::TCP_PORT).as_str());
+            match resolve_peer(peer).await {
+                Some(addr) if !peers.contains(&addr) => peers.push(addr),
+                Some(_) => (),
+                None => warn!("[DOWNLOAD] Couldn't resolve peer {}", peer),
+            }
+        }
 
+        for peer in peers {
             let packet = Packet {
                 packet_type: PacketType::FileCheck,
-                thread_id: self.id,
-                dest_ip: String::new(),
+                dest_ip: peer,
                 from_ip: String::new(),
-                content: self.info.filename.clone(),
-            };
-
-            // Send request
-            sender.send(packet).await.unwrap();
-       
-            // Wait for response
-            let packet = match receiver.recv().await {
-                Some(p) => p,
-                None => continue
+                info_hash: self.hash.clone(),
+                content: Vec::new(),
             };
 
-            // Add peer to active list if they confirm
-            if packet.packet_type == PacketType::FileConfirm {
-                active.push(peer.clone());
-            }
+            // Runs before we start taking packets, so
+            // waiting here never holds up the manager
+            sender.send(packet).await?;
         }
 
-        active
+        Ok(())
     }
 
-    // What if a peer is assigned a piece it doesn't have?
-    // -> Then they never had the full file to begin with
-    // What if peer fails to send their assigned piece?
-    // -> We need to have some method to ask a different peer
-    //    for the same piece
-    pub async fn assign_pieces(
-        &mut self,
-        valid_peers: Vec<String>,
-        sender: &mpsc::Sender<Packet>
-    ) {
-        let pieces: u64 = self.info.size / 512000;
-        let peer_count: u64 = valid_peers.len() as u64;
-        let iter = std::cmp::min(valid_peers.len() as u64, pieces);
-
-        // Send out requests for pieces
-        for i in 0..iter {
-            let index = (i % peer_count) as usize;
-            let peer = valid_peers.get(index).unwrap();
-
-            let mut addr: String = peer.clone();
-            addr.push_str(format!(":{}", crate::TCP_PORT).as_str());
-           
-            let req = PieceRequest {
-                dest_ip: addr.clone(),
-                filename: self.info.filename.clone(),
-                location: i
-            };
-            let req = serde_json::to_string(&req).unwrap();
+    // Ask peers we heard about from somewhere other than
+    // the torrent which pieces they have. Answers come in
+    // as Bitfields and are handled like any other.
+    fn check_peers(&self, peers: &[String], sender: &mpsc::Sender<Packet>) -> Result<()> {
+        let known = self.picker.peer_list();
+        let room = self.max_peers.saturating_sub(known.len());
 
+        for peer in peers.iter().filter(|p| !known.contains(p)).take(room) {
             let packet = Packet {
-                packet_type: PacketType::PieceRequest,
-                thread_id: self.id,
-                dest_ip: addr,
+                packet_type: PacketType::FileCheck,
+                dest_ip: peer.clone(),
                 from_ip: String::new(),
-                content: req,
+                info_hash: self.hash.clone(),
+                content: Vec::new(),
             };
 
-            sender.send(packet).await.unwrap();
+            forward(sender, packet)?;
         }
 
-        // Let peers know that we are done
-        // assigning pieces
-        for peer in &valid_peers {
-            let mut addr: String = peer.clone();
-            addr.push_str(format!(":{}", crate::TCP_PORT).as_str());
-            
+        Ok(())
+    }
+
+    async fn announce(&mut self, udp: &UdpSocket, event: Event) {
+        let left = self.info.bytes_left(&self.have.read().unwrap());
+        self.announcer.announce(udp, &self.stats, left, event).await;
+    }
+
+    // Tell the peers we download from about each other
+    fn exchange_peers(&mut self, sender: &mpsc::Sender<Packet>) -> Result<()> {
+        for packet in self.pex.messages(&self.hash, &self.picker.peer_list()) {
+            forward(sender, packet)?;
+        }
+
+        Ok(())
+    }
+
+    // Let every peer we know about know
+    // that we have finished a piece
+    fn announce_have(&self, location: u64, sender: &mpsc::Sender<Packet>) -> Result<()> {
+        for peer in self.picker.peer_list() {
             let packet = Packet {
-                packet_type: PacketType::DownloadComplete,
-                thread_id: 0,
-                dest_ip: addr,
+                packet_type: PacketType::Have,
+                dest_ip: peer,
                 from_ip: String::new(),
-                content: String::new(),
+                info_hash: self.hash.clone(),
+                content: location.to_be_bytes().to_vec(),
             };
 
-            sender.send(packet).await.unwrap();
+            forward(sender, packet)?;
+        }
+
+        Ok(())
+    }
+
+    // Update what we know about a peer's pieces
+    fn handle_availability(&mut self, packet: &Packet) {
+        let peer = packet.from_ip.clone();
+
+        match packet.packet_type {
+            PacketType::Have => {
+                let index: [u8; 8] = match packet.content.as_slice().try_into() {
+                    Ok(i) => i,
+                    Err(_) => return,
+                };
+                self.picker.peer_has(&peer, u64::from_be_bytes(index));
+            },
+            // Answers to FileCheck. FileConfirm means the
+            // peer has the whole thing.
+            PacketType::Bitfield | PacketType::FileConfirm => {
+                // New peers only while there's room for them
+                let known = self.picker.peer_list().contains(&peer);
+                if !known && self.picker.peer_list().len() >= self.max_peers {
+                    return;
+                }
+
+                let piece_count = self.info.piece_count();
+                let field = match packet.packet_type {
+                    PacketType::FileConfirm => Some(Bitfield::full(piece_count)),
+                    _ => Bitfield::from_bytes(&packet.content, piece_count),
+                };
+                if let Some(field) = field {
+                    self.picker.add_peer(&peer, field.to_vec());
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // Send out whatever requests the picker
+    // currently has room for. Peers serve each
+    // request as soon as it arrives.
+    fn schedule_requests(&mut self, sender: &mpsc::Sender<Packet>) -> Result<()> {
+        // Over the download limit, wait for it to recover
+        if !self.download.is_available() {
+            return Ok(());
+        }
+
+        // A request the manager had no room for
+        // is released to be asked for again
+        for (peer, location) in self.picker.next_requests() {
+            if !self.request_piece(&peer, location, sender)? {
+                self.picker.piece_failed(location);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn request_piece(
+        &self,
+        addr: &str,
+        location: u64,
+        sender: &mpsc::Sender<Packet>
+    ) -> Result<bool> {
+        let req = PieceRequest {
+            dest_ip: addr.to_string(),
+            location,
+            transport: TRANSPORT,
+            udp_port: self.udp_port,
+        };
+        let req = serde_json::to_vec(&req)?;
+
+        let packet = Packet {
+            packet_type: PacketType::PieceRequest,
+            dest_ip: addr.to_string(),
+            from_ip: String::new(),
+            info_hash: self.hash.clone(),
+            content: req,
+        };
+
+        forward(sender, packet)
+    }
+
+    // Acknowledge a block and add it to its piece.
+    // Returns the piece once all of its blocks are in.
+    async fn handle_block(&mut self, packet: &Packet, udp: &UdpSocket) -> Option<(u64, Vec<u8>)> {
+        let block = decode_block(packet)?;
+        if block.piece >= self.info.piece_count() {
+            return None;
+        }
+
+        // Always ack, even duplicates, so the
+        // seeder stops resending the block
+        if let Ok(bytes) = encode_datagram(encode_ack(&block)) {
+            let _ = udp.send_to(&bytes, &packet.from_ip).await;
+        }
+
+        if self.have.read().unwrap().get(block.piece) {
+            return None;
+        }
+
+        let location = block.piece;
+        let expected = block_count(self.info.piece_size(location));
+        let data = self.assembler.add_block(block, expected)?;
+
+        Some((location, data))
+    }
+
+    fn verify_piece(&self, location: u64, bytes: &[u8]) -> Result<()> {
+        let expected_hash = &self.info.pieces[location as usize];
+        if bytes.len() as u64 != self.info.piece_size(location)
+            || hash_piece(bytes) != *expected_hash
+        {
+            return Err(Error::Integrity(format!("Piece {} failed verification", location)));
+        }
+
+        Ok(())
+    }
+
+    // Verify a complete piece and write it to disk.
+    // A bad piece is only the sending peer's problem,
+    // errors here mean the whole download can't go on.
+    fn process_piece(
+        &mut self,
+        location: u64,
+        bytes: &[u8],
+        sender: &mpsc::Sender<Packet>
+    ) -> Result<()> {
+        // Ignore pieces we don't know about
+        // or already have
+        if location >= self.info.piece_count() || self.have.read().unwrap().get(location) {
+            return Ok(());
+        }
+        self.download.take(bytes.len() as u64);
+
+        // Verify piece before it touches the disk
+        if let Err(e) = self.verify_piece(location, bytes) {
+            warn!("[DOWNLOAD] {}", e);
+            self.picker.piece_failed(location);
+            return Ok(());
+        }
+
+        // Write data to correct position,
+        // possibly spanning several files
+        let index = self.info.piece_length * location;
+        self.storage.write_at(index, bytes)?;
+        self.stats.downloaded.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+        self.picker.piece_received(location);
+        self.have.write().unwrap().set(location);
+        self.save_state();
+        self.announce_have(location, sender)
+    }
+
+    // Persist which pieces are done so an
+    // interrupted download can be resumed
+    fn save_state(&self) {
+        let have = self.have.read().unwrap().clone();
+        if let Err(e) = save_resume(&self.root, &self.info, &self.storage, &have) {
+            error!("[DOWNLOAD] Failed to save resume state: {}", e);
         }
     }
 
     pub async fn receive(
         &mut self,
-        receiver: &mut mpsc::Receiver<Packet> 
-    ) {
-        // Create sparse file
-        // --- WARNING ---
-        // Test this on windows! This might only be a Linux thing!
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(format!("./downloads/{}", self.info.filename))
-            .unwrap();
-        file.seek(SeekFrom::Start(self.info.size)).unwrap();
-        file.write_all(&[0]).unwrap();
-
-        // Calculate important numbers
-        let mut expected_pieces: u64 = self.info.size / 512000;
-        if self.info.size % 512000 != 0 {
-            expected_pieces += 1;
-        }
-        let mut current_pieces: u64 = 0;
+        receiver: &mut mpsc::Receiver<Packet>,
+        sender: &mpsc::Sender<Packet>,
+        udp: &UdpSocket,
+        shutdown: &CancellationToken
+    ) -> Result<bool> {
+        // Create directory tree and sparse files
+        self.storage.allocate()?;
+
+        // Seeders send blocks to whatever port we ask for
+        self.udp_port = udp.local_addr().map(|a| a.port()).unwrap_or(0);
 
         // Write data to disk
-        while current_pieces < expected_pieces {
-            let packet: Packet = match receiver.recv().await {
-                Some(packet) => packet,
-                None => continue
-            };
+        let mut deadline: Option<Instant> = None;
+        while !self.picker.is_complete() {
+            // When shutting down, nothing new is asked for and
+            // only pieces already on their way are waited for
+            if shutdown.is_cancelled() {
+                let deadline = *deadline.get_or_insert(Instant::now() + SHUTDOWN_GRACE);
+                if !self.picker.has_pending() || Instant::now() >= deadline {
+                    break;
+                }
+            }
 
-            // Currently, getting anything other than a piece
-            // is meaningless. This will probably change.        
-            if packet.packet_type != PacketType::PieceDelivery {
-                continue;
+            // Stalled requests go back to the picker
+            // so they can be given to another peer
+            for location in self.picker.expire() {
+                debug!("[DOWNLOAD] Request for piece {} timed out", location);
+            }
+            if !self.paused.load(Ordering::Relaxed) && !shutdown.is_cancelled() {
+                self.schedule_requests(sender)?;
             }
 
-            // Get file contents
-            let bytes = packet.content.as_bytes();
+            // Trackers may know about peers the torrent doesn't
+            if self.announcer.is_due() && !shutdown.is_cancelled() {
+                self.announce(udp, Event::None).await;
+            }
+            if self.pex.is_due() && !shutdown.is_cancelled() {
+                self.exchange_peers(sender)?;
+            }
 
-            // Write data to correct position
-            let location: u64 = match packet.content.parse() {
-                Ok(val) => val,
-                Err(_) => continue,
+            // Wake up periodically even if nothing
+            // arrives so stalled requests get noticed
+            let packet: Packet = tokio::select! {
+                result = timeout(TICK, receiver.recv()) => match result {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                },
+                _ = shutdown.cancelled(), if deadline.is_none() => continue,
             };
-            let index = 512000 * location;
-            file.seek(SeekFrom::Start(index)).unwrap();
-            file.write_all(&bytes).unwrap();
 
-            current_pieces += 1;
+            match packet.packet_type {
+                PacketType::PieceDelivery => {
+                    if let Some((location, bytes)) = decode_piece(&packet.content) {
+                        self.process_piece(location, bytes, sender)?;
+                    }
+                },
+                PacketType::Block => {
+                    if let Some((location, bytes)) = self.handle_block(&packet, udp).await {
+                        self.process_piece(location, &bytes, sender)?;
+                    }
+                },
+                PacketType::Have | PacketType::Bitfield | PacketType::FileConfirm => {
+                    self.handle_availability(&packet);
+                },
+                PacketType::AnnounceReply => {
+                    if let Some(reply) = self.announcer.handle_reply(&packet) {
+                        self.check_peers(&reply.peers, sender)?;
+                    }
+                },
+                PacketType::PeersFound => {
+                    if let Ok(peers) = serde_json::from_slice::<Vec<String>>(&packet.content) {
+                        self.check_peers(&peers, sender)?;
+                    }
+                },
+                PacketType::Pex => {
+                    let peers = self.pex.receive(&packet);
+                    self.check_peers(&peers, sender)?;
+                },
+                // Stop asking a peer we can't reach. Its
+                // pending pieces go to whoever else has them.
+                PacketType::PeerUnreachable => {
+                    info!("[DOWNLOAD] Dropping unreachable peer {}", packet.from_ip);
+                    self.picker.remove_peer(&packet.from_ip);
+                },
+                _ => (),
+            }
         }
+
+        // Already saved after every piece, but
+        // make sure nothing is lost on the way out
+        self.save_state();
+
+        // False if stopped before finishing
+        let complete = self.picker.is_complete();
+        let event = if complete { Event::Completed } else { Event::Stopped };
+        self.announce(udp, event).await;
+
+        Ok(complete)
     }    
 }
@@ -1,107 +1,416 @@
-use std::fs::{File, read};
-use std::error::Error;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use tokio::sync::mpsc;
 use tokio::net::UdpSocket;
+use tokio::time::{sleep_until, timeout};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     Packet, PacketType, 
     TorrentInfo,
     PieceRequest
 };
+use crate::core::structs::SHUTDOWN_GRACE;
+use crate::core::address::peer_addr;
+use crate::core::bitfield::Bitfield;
+use crate::core::rate::RateLimit;
+use crate::core::codec::{decode_ack, encode_block, encode_datagram, encode_piece};
+use crate::core::transfer::{split_piece, BlockSender, Transport};
+use crate::core::tracker::{Announcer, Event, TransferStats};
+use crate::core::pex::Pex;
+use crate::core::pool::forward;
+use crate::core::metadata::{decode_request, encode_chunk};
+use crate::file::cache::LruCache;
+use crate::file::storage::Storage;
+use crate::file::torrent::{info_hash, parse_torrent_file};
+use crate::error::Result;
+use crate::error;
 
-pub fn get_file_bytes(filename: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-    let bytes = read(filename)?; 
+// Recently sent pieces kept in memory, since peers
+// tend to ask for the same pieces around the same time
+const PIECE_CACHE_SIZE: usize = 8;
 
-    Ok(bytes.to_vec())
-}
+// How often to check for blocks that need resending
+const TRANSFER_TICK: Duration = Duration::from_millis(50);
 
-pub fn get_piece(data: &[u8], piece: u64) -> Vec<u8> {
-    let start = piece * 512000;
-    let mut result: Vec<u8> = Vec::with_capacity(512000);
-    
-    let mut i = 0;
-    while i < 512000 {
-        let index = start + i;
-        result.push(data[index as usize]);
-        i += 1;
-    }
-    
-    result
+// Peers that haven't asked us for anything in this
+// long are left out of peer exchange
+const PEER_IDLE: Duration = Duration::from_secs(300);
+
+// A torrent that is still being downloaded but can
+// already serve the pieces it has to other peers
+pub struct PartialSeed {
+    pub info: TorrentInfo,
+    pub root: PathBuf,
+    pub have: Arc<RwLock<Bitfield>>,
+    pub upload: RateLimit,
+    pub port: u16,
+    pub stats: Arc<TransferStats>,
 }
 
 pub struct SeedThread {
-    id: u64,
     info: TorrentInfo,
+    hash: String,
+    storage: Storage,
+    have: Arc<RwLock<Bitfield>>,
+    pieces: LruCache<u64, Arc<Vec<u8>>>,
+    // Block transfers in progress, keyed by peer UDP address
+    transfers: HashMap<String, BlockSender>,
+    // UDP address of every peer we've sent blocks to,
+    // keyed by the address the peer listens on
+    udp_peers: HashMap<String, String>,
+    // When each peer last checked the file or asked
+    // for a piece, keyed by the address it listens on
+    peers: HashMap<String, Instant>,
+    upload: RateLimit,
+    // Set from the control socket. Requests are
+    // ignored and nothing is sent while paused.
+    paused: Arc<AtomicBool>,
+    stats: Arc<TransferStats>,
+    announcer: Announcer,
+    pex: Pex,
 }
 
 impl SeedThread {
-    pub fn new(id: u64, filename: &str) -> Result<Self, Box<dyn Error>> {
-        let file = std::fs::read_to_string(filename)?;
-        let info: TorrentInfo = serde_json::from_str(file.as_str())?;
+    // Contents are read from under root, the same directory a
+    // download of the torrent saves to. port is the TCP port
+    // we listen on, for trackers.
+    pub fn new(filename: &str, root: &Path, upload: RateLimit, port: u16) -> Result<Self> {
+        let info: TorrentInfo = parse_torrent_file(filename)?;
+        let have = Bitfield::full(info.piece_count());
 
+        let storage = Storage::new(root, &info)?;
+
+        let hash = info_hash(&info);
         Ok(Self {
-            id,
+            announcer: Announcer::new(&info.trackers, &hash, port),
+            hash,
             info, 
+            storage,
+            have: Arc::new(RwLock::new(have)),
+            pieces: LruCache::new(PIECE_CACHE_SIZE),
+            transfers: HashMap::new(),
+            udp_peers: HashMap::new(),
+            peers: HashMap::new(),
+            upload,
+            paused: Arc::new(AtomicBool::new(false)),
+            stats: Arc::new(TransferStats::default()),
+            pex: Pex::new(),
         })
     }
 
-    pub async fn get_assignments(
-        &self, 
-        receiver: &mut mpsc::Receiver<Packet>
-    ) -> Vec<PieceRequest> {
-        let mut piece_assignments: Vec<PieceRequest> = Vec::new();
+    pub fn from_partial(partial: PartialSeed) -> Result<Self> {
+        let storage = Storage::new(&partial.root, &partial.info)?;
 
-        // Read from receiver until all piece 
-        // assignments are received
-        loop {
-            let packet: Packet = match receiver.try_recv() {
-                Ok(packet) => packet,
-                _ => continue
-            };
+        let hash = info_hash(&partial.info);
+        Ok(Self {
+            announcer: Announcer::new(&partial.info.trackers, &hash, partial.port),
+            hash,
+            info: partial.info,
+            storage,
+            have: partial.have,
+            pieces: LruCache::new(PIECE_CACHE_SIZE),
+            transfers: HashMap::new(),
+            udp_peers: HashMap::new(),
+            peers: HashMap::new(),
+            upload: partial.upload,
+            paused: Arc::new(AtomicBool::new(false)),
+            stats: partial.stats,
+            pex: Pex::new(),
+        })
+    }
 
-            match packet.packet_type {
-                PacketType::PieceRequest => {
-                    let req: PieceRequest = match serde_json::from_str(&packet.content) {
-                        Ok(p) => p,
-                        Err(_) => continue
-                    };
-
-                    piece_assignments.push(req);
-                },
-                PacketType::RequestDone => break,
-                _ => (),
-            }
+    pub fn info_hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn have(&self) -> Arc<RwLock<Bitfield>> {
+        self.have.clone()
+    }
+
+    pub fn pause_flag(&self) -> Arc<AtomicBool> {
+        self.paused.clone()
+    }
+
+    // Read a single piece from disk, or from the cache
+    // if it was sent recently. Only the bytes of the piece
+    // are read, never the whole file.
+    fn read_piece(&mut self, location: u64) -> std::io::Result<Arc<Vec<u8>>> {
+        if let Some(piece) = self.pieces.get_mut(&location) {
+            return Ok(piece.clone());
         }
 
-        piece_assignments
+        let offset = location * self.info.piece_length;
+        let piece = Arc::new(self.storage.read_at(offset, self.info.piece_size(location))?);
+        self.pieces.put(location, piece.clone());
+
+        Ok(piece)
+    }
+
+    async fn announce(&mut self, udp: &UdpSocket, event: Event) {
+        let left = self.info.bytes_left(&self.have.read().unwrap());
+        self.announcer.announce(udp, &self.stats, left, event).await;
     }
 
-    pub async fn send_piece(
+    // Tell the peers we upload to about each other
+    fn exchange_peers(&mut self, sender: &mpsc::Sender<Packet>) -> Result<()> {
+        self.peers.retain(|_, seen| seen.elapsed() < PEER_IDLE);
+
+        let peers: Vec<String> = self.peers.keys().cloned().collect();
+        for packet in self.pex.messages(&self.hash, &peers) {
+            forward(sender, packet)?;
+        }
+
+        Ok(())
+    }
+
+    // Tell a peer which pieces we can give them
+    fn answer_file_check(
+        &self,
+        packet: &Packet,
+        sender: &mpsc::Sender<Packet>
+    ) -> Result<()> {
+        let have = self.have.read().unwrap().clone();
+
+        let reply_type = if have.is_empty() {
+            PacketType::FileDeny
+        }
+        else {
+            PacketType::Bitfield
+        };
+
+        let reply = Packet {
+            packet_type: reply_type.clone(),
+            dest_ip: packet.from_ip.clone(),
+            from_ip: String::new(),
+            info_hash: self.hash.clone(),
+            content: match reply_type {
+                PacketType::Bitfield => have.to_bytes(),
+                _ => Vec::new(),
+            },
+        };
+
+        forward(sender, reply)?;
+        Ok(())
+    }
+
+    // Send part of the torrent itself to a peer
+    // that only knows its info hash
+    fn answer_metadata(
+        &self,
+        packet: &Packet,
+        sender: &mpsc::Sender<Packet>
+    ) -> Result<()> {
+        let metadata = serde_json::to_vec(&self.info)?;
+        let chunk = decode_request(&packet.content)
+            .and_then(|index| encode_chunk(&metadata, index));
+
+        let reply = Packet {
+            packet_type: match chunk {
+                Some(_) => PacketType::MetadataReply,
+                None => PacketType::FileDeny,
+            },
+            dest_ip: packet.from_ip.clone(),
+            from_ip: String::new(),
+            info_hash: self.hash.clone(),
+            content: chunk.unwrap_or_default(),
+        };
+
+        forward(sender, reply)?;
+        Ok(())
+    }
+
+    // Serve a single piece request, either as acknowledged
+    // blocks over UDP or as a whole piece over TCP. A bad
+    // request or unreadable piece only fails that request.
+    fn handle_request(
         &mut self,
-        request: PieceRequest, 
-        udp: &Arc<UdpSocket>,
-    ) {
-        match get_file_bytes(&request.filename) {
-            Ok(file_bytes) => {
-                let piece_data = get_piece(&file_bytes, request.location);
+        packet: &Packet,
+        sender: &mpsc::Sender<Packet>
+    ) -> Result<()> {
+        let request: PieceRequest = match serde_json::from_slice(&packet.content) {
+            Ok(r) => r,
+            Err(_) => return Ok(()),
+        };
+
+        // Only serve pieces we actually have
+        if !self.have.read().unwrap().get(request.location) {
+            return Ok(());
+        }
+
+        // The path in the request is never trusted,
+        // pieces are always read from our own storage
+        let piece_data = match self.read_piece(request.location) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("[SEED] Failed to read piece {}: {}", request.location, e);
+                return Ok(());
+            }
+        };
+        self.stats.uploaded.fetch_add(piece_data.len() as u64, Ordering::Relaxed);
+
+        match request.transport {
+            Transport::Tcp => {
+                // Whole pieces can't be held back, but
+                // still count against the limit
+                self.upload.take(piece_data.len() as u64);
 
                 let packet = Packet {
                     packet_type: PacketType::PieceDelivery,
-                    thread_id: self.id,
-                    dest_ip: request.dest_ip.clone(),
+                    dest_ip: packet.from_ip.clone(),
                     from_ip: String::new(),
-                    content: String::from_utf8(piece_data).unwrap()
+                    info_hash: self.hash.clone(),
+                    content: encode_piece(request.location, piece_data.as_slice()),
                 };
-                let data = serde_json::to_string(&packet).unwrap();
-                let bytes = data.as_bytes();
-                
-                udp.send_to(bytes, request.dest_ip)
-                .await
-                .expect("Failed to send UDP packet");
+
+                forward(sender, packet)?;
             },
-            Err(_) => ()
+            Transport::Udp => {
+                let ip = match packet.from_ip.parse::<SocketAddr>() {
+                    Ok(a) => a.ip(),
+                    Err(_) => return Ok(()),
+                };
+                let udp_addr = peer_addr(ip, request.udp_port);
+
+                let blocks = split_piece(request.location, &self.hash, &piece_data);
+                self.transfers
+                    .entry(udp_addr.clone())
+                    .or_default()
+                    .push(blocks);
+                self.udp_peers.insert(packet.from_ip.clone(), udp_addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Send whatever blocks the transfers are ready to send
+    async fn pump_transfers(&mut self, udp: &Arc<UdpSocket>) {
+        for (addr, transfer) in self.transfers.iter_mut() {
+            for block in transfer.poll(&self.upload) {
+                let bytes = match encode_datagram(encode_block(&block)) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        error!("[SEED] Failed to encode block: {}", e);
+                        continue;
+                    }
+                };
+
+                // A lost datagram is no different from a dropped
+                // one, the block is retransmitted either way
+                let _ = udp.send_to(&bytes, addr).await;
+            }
+        }
+
+        self.transfers.retain(|_, t| !t.is_idle());
+    }
+
+    pub async fn run(
+        &mut self,
+        receiver: &mut mpsc::Receiver<Packet>,
+        sender: &mpsc::Sender<Packet>,
+        udp: &Arc<UdpSocket>,
+        shutdown: &CancellationToken
+    ) -> Result<()> {
+        let mut deadline: Option<Instant> = None;
+        loop {
+            // When shutting down, no new requests are taken
+            // and only blocks already being sent are finished
+            if shutdown.is_cancelled() {
+                let deadline = *deadline.get_or_insert(Instant::now() + SHUTDOWN_GRACE);
+                if self.transfers.is_empty() || Instant::now() >= deadline {
+                    break;
+                }
+            }
+
+            // A download announces for itself until it's
+            // done, then its seed thread takes over
+            if self.announcer.is_due() && !shutdown.is_cancelled() {
+                if self.have.read().unwrap().is_complete() {
+                    self.announce(udp, Event::None).await;
+                }
+                else {
+                    self.announcer.postpone();
+                }
+            }
+
+            if self.pex.is_due() && !shutdown.is_cancelled() {
+                self.exchange_peers(sender)?;
+            }
+
+            // Only wake up on a timer while blocks are
+            // in flight, otherwise just wait for packets
+            let packet: Option<Packet> = if self.transfers.is_empty() {
+                let announce_at = self.announcer.next().into();
+                let pex_at = self.pex.next().into();
+                tokio::select! {
+                    packet = receiver.recv() => match packet {
+                        Some(p) => Some(p),
+                        None => break,
+                    },
+                    _ = shutdown.cancelled() => None,
+                    _ = sleep_until(announce_at), if self.announcer.has_trackers() => None,
+                    _ = sleep_until(pex_at), if !self.peers.is_empty() => None,
+                }
+            }
+            else {
+                match timeout(TRANSFER_TICK, receiver.recv()).await {
+                    Ok(Some(p)) => Some(p),
+                    Ok(None) => break,
+                    Err(_) => None,
+                }
+            };
+
+            let paused = self.paused.load(Ordering::Relaxed) || shutdown.is_cancelled();
+            if let Some(packet) = packet {
+                match packet.packet_type {
+                    PacketType::FileCheck
+                    | PacketType::PieceRequest
+                    | PacketType::MetadataRequest if paused => (),
+                    PacketType::FileCheck => {
+                        self.peers.insert(packet.from_ip.clone(), Instant::now());
+                        self.answer_file_check(&packet, sender)?;
+                    },
+                    PacketType::PieceRequest => {
+                        self.peers.insert(packet.from_ip.clone(), Instant::now());
+                        self.handle_request(&packet, sender)?;
+                    },
+                    PacketType::MetadataRequest => {
+                        self.answer_metadata(&packet, sender)?;
+                    },
+                    // Only the interval matters, seeds don't need peers
+                    PacketType::AnnounceReply => {
+                        self.announcer.handle_reply(&packet);
+                    },
+                    PacketType::BlockAck => {
+                        if let Some((piece, index)) = decode_ack(&packet.content)
+                            && let Some(transfer) = self.transfers.get_mut(&packet.from_ip)
+                        {
+                            transfer.ack(piece, index);
+                        }
+                    },
+                    // No point sending blocks to a peer that's gone
+                    PacketType::PeerUnreachable => {
+                        self.peers.remove(&packet.from_ip);
+                        if let Some(udp_addr) = self.udp_peers.remove(&packet.from_ip) {
+                            self.transfers.remove(&udp_addr);
+                        }
+                    },
+                    _ => (),
+                }
+            }
+
+            if !paused {
+                self.pump_transfers(udp).await;
+            }
         }
+
+        self.announce(udp, Event::Stopped).await;
+        Ok(())
     }
 }
@@ -1,5 +1,11 @@
+use std::time::Duration;
+
 use serde::{Serialize, Deserialize};
 
+use crate::core::bitfield::Bitfield;
+use crate::core::transfer::Transport;
+use crate::file::torrent::FileNode;
+
 #[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PacketType {
     #[default]
@@ -11,28 +17,164 @@ pub enum PacketType {
     PieceDelivery,      // Peer piece delivery
     RequestDone,        // Peer has finished asking for pieces
     DownloadComplete,   // Stop sending pieces 
+    Bitfield,           // Peer lists every piece it has
+    Have,               // Peer has finished another piece
+    Block,              // Part of a piece sent over UDP
+    BlockAck,           // Block arrived
+    PeerUnreachable,    // Local only, a packet couldn't be delivered
+    Handshake,          // First frame on every connection
+    Announce,           // Tell a tracker about ourselves
+    AnnounceReply,      // Tracker lists other peers
+    Scrape,             // Ask a tracker how big a swarm is
+    ScrapeReply,        // Tracker answers a scrape
+    Dht,                // DHT query or response
+    PeersFound,         // Local only, peers found for a torrent
+    Pex,                // Peers added and dropped since the last one
+    LocalAnnounce,      // Torrent multicast on the LAN
+    MetadataRequest,    // Ask for part of a torrent's metadata
+    MetadataReply,      // Part of a torrent's metadata
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+// Tags used for packet types on the wire.
+// These must never be reordered.
+impl From<PacketType> for u8 {
+    fn from(packet_type: PacketType) -> u8 {
+        match packet_type {
+            PacketType::None => 0,
+            PacketType::FileCheck => 1,
+            PacketType::FileConfirm => 2,
+            PacketType::FileDeny => 3,
+            PacketType::PieceRequest => 4,
+            PacketType::PieceDelivery => 5,
+            PacketType::RequestDone => 6,
+            PacketType::DownloadComplete => 7,
+            PacketType::Bitfield => 8,
+            PacketType::Have => 9,
+            PacketType::Block => 10,
+            PacketType::BlockAck => 11,
+            PacketType::PeerUnreachable => 12,
+            PacketType::Handshake => 13,
+            PacketType::Announce => 14,
+            PacketType::AnnounceReply => 15,
+            PacketType::Scrape => 16,
+            PacketType::ScrapeReply => 17,
+            PacketType::Dht => 18,
+            PacketType::PeersFound => 19,
+            PacketType::Pex => 20,
+            PacketType::LocalAnnounce => 21,
+            PacketType::MetadataRequest => 22,
+            PacketType::MetadataReply => 23,
+        }
+    }
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = u8;
+
+    fn try_from(tag: u8) -> Result<Self, u8> {
+        match tag {
+            0 => Ok(PacketType::None),
+            1 => Ok(PacketType::FileCheck),
+            2 => Ok(PacketType::FileConfirm),
+            3 => Ok(PacketType::FileDeny),
+            4 => Ok(PacketType::PieceRequest),
+            5 => Ok(PacketType::PieceDelivery),
+            6 => Ok(PacketType::RequestDone),
+            7 => Ok(PacketType::DownloadComplete),
+            8 => Ok(PacketType::Bitfield),
+            9 => Ok(PacketType::Have),
+            10 => Ok(PacketType::Block),
+            11 => Ok(PacketType::BlockAck),
+            12 => Ok(PacketType::PeerUnreachable),
+            13 => Ok(PacketType::Handshake),
+            14 => Ok(PacketType::Announce),
+            15 => Ok(PacketType::AnnounceReply),
+            16 => Ok(PacketType::Scrape),
+            17 => Ok(PacketType::ScrapeReply),
+            18 => Ok(PacketType::Dht),
+            19 => Ok(PacketType::PeersFound),
+            20 => Ok(PacketType::Pex),
+            21 => Ok(PacketType::LocalAnnounce),
+            22 => Ok(PacketType::MetadataRequest),
+            23 => Ok(PacketType::MetadataReply),
+            _ => Err(tag),
+        }
+    }
+}
+
+// dest_ip and from_ip are only used for local routing
+// and are never written to the wire (see core::codec).
+// info_hash is the hex encoded hash of the torrent the
+// packet is about, and decides which thread gets it.
+#[derive(Clone)]
 pub struct Packet {
     pub packet_type: PacketType,
-    pub thread_id: u64,
     pub dest_ip: String,
     pub from_ip: String,
-    pub content: String,
+    pub info_hash: String,
+    pub content: Vec<u8>,
 }
 
+// Default size of a single piece in bytes
+pub const PIECE_LENGTH: u64 = 512000;
+
+// How long seed and download threads get to finish
+// pieces already in flight when shutting down
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+fn default_piece_length() -> u64 {
+    PIECE_LENGTH
+}
+
+// Metainfo for a single file or a whole directory tree.
+// filename and size describe the root of the tree.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct TorrentInfo {
     pub filename: String,
     pub created_on: String,
     pub size: u64,
     pub peers: Vec<String>,
+    #[serde(default = "default_piece_length")]
+    pub piece_length: u64,
+    // Hex encoded SHA-1 digest of every piece, in order
+    #[serde(default)]
+    pub pieces: Vec<String>,
+    #[serde(default)]
+    pub files: FileNode,
+    // UDP addresses of trackers that know about more
+    // peers than the ones listed above
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trackers: Vec<String>,
+}
+
+impl TorrentInfo {
+    pub fn piece_count(&self) -> u64 {
+        self.size.div_ceil(self.piece_length)
+    }
+
+    // Size of the given piece. Only the final
+    // piece can be shorter than piece_length.
+    pub fn piece_size(&self, location: u64) -> u64 {
+        let start = location * self.piece_length;
+        std::cmp::min(self.piece_length, self.size.saturating_sub(start))
+    }
+
+    // Bytes still missing, given the pieces we have
+    pub fn bytes_left(&self, have: &Bitfield) -> u64 {
+        (0..self.piece_count())
+            .filter(|location| !have.get(*location))
+            .map(|location| self.piece_size(location))
+            .sum()
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PieceRequest {
     pub dest_ip: String,
-    pub filename: String,
-    pub location: u64
+    pub location: u64,
+    #[serde(default)]
+    pub transport: Transport,
+    // Where blocks go when the transport is UDP
+    #[serde(default)]
+    pub udp_port: u16,
 }
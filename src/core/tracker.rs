@@ -0,0 +1,548 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::core::address::{peer_addr, resolve_addr, DEFAULT_UDP_PORT};
+use crate::core::codec::{decode_datagram, encode_datagram, MAX_DATAGRAM_LEN};
+use crate::core::handshake::peer_id;
+use crate::core::structs::{Packet, PacketType};
+use crate::error::{Error, Result};
+use crate::file::torrent::to_hex;
+use crate::{debug, info, warn};
+
+// A tracker keeps a list of everyone in each torrent's swarm.
+// Peers announce themselves every so often, saying how much
+// they have left, and get some of the others back. Any node
+// started with the tracker command is one, reached on its UDP
+// port with the usual framing. Requests and replies are JSON.
+
+// Announce again this soon if a tracker doesn't answer
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(30);
+
+// Never announce more often than this, whatever a tracker says
+const MIN_INTERVAL: u64 = 30;
+
+// Nor less often than this
+const MAX_INTERVAL: u64 = 60 * 60;
+
+// Most peers handed out for a single announce
+const MAX_REPLY_PEERS: usize = 50;
+
+// Most torrents tracked, and peers kept for each
+const MAX_SWARMS: usize = 1000;
+const MAX_SWARM_PEERS: usize = 1000;
+
+// Replies are far bigger than requests, so answer any
+// one address only so often. Otherwise a spoofed source
+// turns the tracker into an amplifier.
+const MAX_SOURCE_REPLIES: u32 = 50;
+const SOURCE_WINDOW: Duration = Duration::from_secs(10);
+const MAX_SOURCES: usize = 10000;
+
+// How long the scrape command waits for an answer
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    // Just checking in
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AnnounceRequest {
+    // Hex encoded, the same one we use in handshakes
+    pub peer_id: String,
+    // TCP port we listen on
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Event,
+    // Most peers we want back
+    pub num_want: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AnnounceReply {
+    // Seconds until the next announce
+    pub interval: u64,
+    // Peers with and without the whole torrent
+    pub complete: u64,
+    pub incomplete: u64,
+    pub peers: Vec<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct ScrapeReply {
+    pub complete: u64,
+    pub incomplete: u64,
+    // Times the torrent was downloaded start to finish
+    pub downloaded: u64,
+}
+
+// Bytes moved for a single torrent. Shared by its download
+// and seed threads so announces can report both.
+#[derive(Default)]
+pub struct TransferStats {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+}
+
+// Announces a single torrent to each of its trackers.
+// Replies come back like any other UDP packet and
+// are handed to handle_reply.
+pub struct Announcer {
+    trackers: Vec<String>,
+    // What the trackers resolved to on the last
+    // announce. Replies from anywhere else are dropped.
+    resolved: HashSet<String>,
+    hash: String,
+    // TCP port we listen on
+    port: u16,
+    next: Instant,
+    started: bool,
+}
+
+impl Announcer {
+    pub fn new(trackers: &[String], hash: &str, port: u16) -> Self {
+        Self {
+            trackers: trackers.to_vec(),
+            resolved: HashSet::new(),
+            hash: hash.to_string(),
+            port,
+            next: Instant::now(),
+            started: false,
+        }
+    }
+
+    pub fn has_trackers(&self) -> bool {
+        !self.trackers.is_empty()
+    }
+
+    pub fn next(&self) -> Instant {
+        self.next
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.has_trackers() && Instant::now() >= self.next
+    }
+
+    // For torrents that have nothing to announce yet
+    pub fn postpone(&mut self) {
+        self.next = Instant::now() + ANNOUNCE_RETRY;
+    }
+
+    // The first announce is always Started, and Completed
+    // means nothing to a tracker that never heard of us.
+    // Stopped always goes out, since the download side of
+    // a torrent may have announced it for us.
+    pub async fn announce(
+        &mut self,
+        udp: &UdpSocket,
+        stats: &TransferStats,
+        left: u64,
+        event: Event
+    ) {
+        let event = match (self.started, event) {
+            (false, Event::Completed) => return,
+            (false, Event::Stopped) => Event::Stopped,
+            (false, _) => Event::Started,
+            (true, Event::Started) => Event::None,
+            (true, e) => e,
+        };
+        self.started = event != Event::Stopped;
+        self.postpone();
+
+        let request = AnnounceRequest {
+            peer_id: to_hex(&peer_id()),
+            port: self.port,
+            uploaded: stats.uploaded.load(Ordering::Relaxed),
+            downloaded: stats.downloaded.load(Ordering::Relaxed),
+            left,
+            event,
+            num_want: MAX_REPLY_PEERS,
+        };
+        let content = match serde_json::to_vec(&request) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        self.resolved.clear();
+        for tracker in &self.trackers {
+            let addr = match resolve_addr(tracker, DEFAULT_UDP_PORT).await {
+                Some(a) => a,
+                None => {
+                    warn!("[TRACKER] Couldn't resolve tracker {}", tracker);
+                    continue;
+                }
+            };
+            self.resolved.insert(addr.clone());
+
+            let packet = Packet {
+                packet_type: PacketType::Announce,
+                dest_ip: addr.clone(),
+                from_ip: String::new(),
+                info_hash: self.hash.clone(),
+                content: content.clone(),
+            };
+            if let Ok(bytes) = encode_datagram(packet) {
+                let _ = udp.send_to(&bytes, &addr).await;
+            }
+        }
+    }
+
+    pub fn handle_reply(&mut self, packet: &Packet) -> Option<AnnounceReply> {
+        if !self.resolved.contains(&packet.from_ip) {
+            debug!("[TRACKER] Ignoring announce reply from {}", packet.from_ip);
+            return None;
+        }
+
+        let reply: AnnounceReply = serde_json::from_slice(&packet.content).ok()?;
+        let interval = Duration::from_secs(reply.interval.clamp(MIN_INTERVAL, MAX_INTERVAL));
+        self.next = Instant::now().checked_add(interval)?;
+
+        debug!("[TRACKER] {} has {} seeds and {} others, got {} peers",
+            packet.from_ip, reply.complete, reply.incomplete, reply.peers.len());
+
+        Some(reply)
+    }
+}
+
+// Ask a tracker how big a torrent's swarm is
+pub async fn scrape(tracker: &str, hash: &str) -> Result<ScrapeReply> {
+    let addr: SocketAddr = resolve_addr(tracker, DEFAULT_UDP_PORT).await
+        .and_then(|a| a.parse().ok())
+        .ok_or(Error::Peer(format!("Couldn't resolve tracker {}", tracker)))?;
+
+    let local = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let udp = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+
+    let packet = Packet {
+        packet_type: PacketType::Scrape,
+        dest_ip: addr.to_string(),
+        from_ip: String::new(),
+        info_hash: hash.to_string(),
+        content: Vec::new(),
+    };
+    udp.send_to(&encode_datagram(packet)?, addr).await?;
+
+    // Anything else that turns up is ignored
+    let mut buf: Vec<u8> = vec![0; MAX_DATAGRAM_LEN];
+    let wait = async {
+        loop {
+            let (len, from) = udp.recv_from(&mut buf).await?;
+            let packet = match decode_datagram(&buf[..len]) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            if from == addr && packet.packet_type == PacketType::ScrapeReply && packet.info_hash == hash {
+                return Ok(serde_json::from_slice(&packet.content)?);
+            }
+        }
+    };
+
+    match timeout(SCRAPE_TIMEOUT, wait).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Peer(format!("No answer from tracker {}", tracker))),
+    }
+}
+
+struct SwarmPeer {
+    addr: String,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    // Keyed by peer id
+    peers: HashMap<String, SwarmPeer>,
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn last_seen(&self) -> Option<Instant> {
+        self.peers.values().map(|p| p.last_seen).max()
+    }
+
+    // Peers with and without the whole torrent
+    fn counts(&self) -> (u64, u64) {
+        let complete = self.peers.values().filter(|p| p.left == 0).count() as u64;
+        (complete, self.peers.len() as u64 - complete)
+    }
+}
+
+// Everyone who announced, keyed by info hash. Any torrent
+// is tracked, the tracker doesn't need the torrent file.
+struct Tracker {
+    swarms: HashMap<String, Swarm>,
+    // Replies sent to each address, and since when
+    sources: HashMap<IpAddr, (Instant, u32)>,
+    interval: Duration,
+}
+
+impl Tracker {
+    fn new(interval: Duration) -> Self {
+        Self {
+            swarms: HashMap::new(),
+            sources: HashMap::new(),
+            interval,
+        }
+    }
+
+    // Whether from may be sent another reply
+    fn allow_reply(&mut self, from: IpAddr) -> bool {
+        let from = from.to_canonical();
+        if self.sources.len() >= MAX_SOURCES && !self.sources.contains_key(&from) {
+            self.sources.retain(|_, (since, _)| since.elapsed() < SOURCE_WINDOW);
+            if self.sources.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+
+        let (since, replies) = self.sources.entry(from).or_insert((Instant::now(), 0));
+        if since.elapsed() >= SOURCE_WINDOW {
+            *since = Instant::now();
+            *replies = 0;
+        }
+        if *replies >= MAX_SOURCE_REPLIES {
+            return false;
+        }
+        *replies += 1;
+
+        true
+    }
+
+    fn announce(&mut self, hash: &str, from: SocketAddr, request: AnnounceRequest) -> AnnounceReply {
+        // Make room by forgetting the torrent nobody
+        // has announced for in the longest time
+        if self.swarms.len() >= MAX_SWARMS && !self.swarms.contains_key(hash) {
+            let oldest = self.swarms.iter()
+                .min_by_key(|(_, swarm)| swarm.last_seen())
+                .map(|(hash, _)| hash.clone());
+            if let Some(oldest) = oldest {
+                self.swarms.remove(&oldest);
+            }
+        }
+
+        let swarm = self.swarms.entry(hash.to_string()).or_default();
+
+        if request.event == Event::Stopped {
+            swarm.peers.remove(&request.peer_id);
+        }
+        else {
+            if request.event == Event::Completed {
+                swarm.downloaded += 1;
+            }
+
+            // Make room by forgetting whoever announced longest ago
+            if swarm.peers.len() >= MAX_SWARM_PEERS && !swarm.peers.contains_key(&request.peer_id) {
+                let oldest = swarm.peers.iter()
+                    .min_by_key(|(_, p)| p.last_seen)
+                    .map(|(id, _)| id.clone());
+                if let Some(oldest) = oldest {
+                    swarm.peers.remove(&oldest);
+                }
+            }
+
+            // Peers are reached on the port they listen on,
+            // not the one the announce came from
+            swarm.peers.insert(request.peer_id.clone(), SwarmPeer {
+                addr: peer_addr(from.ip(), request.port),
+                left: request.left,
+                last_seen: Instant::now(),
+            });
+        }
+
+        // Seeds have no use for other seeds
+        let mut peers: Vec<String> = swarm.peers.iter()
+            .filter(|(id, _)| **id != request.peer_id)
+            .filter(|(_, p)| request.left > 0 || p.left > 0)
+            .map(|(_, p)| p.addr.clone())
+            .collect();
+        peers.shuffle(&mut rand::rng());
+        peers.truncate(request.num_want.min(MAX_REPLY_PEERS));
+
+        let (complete, incomplete) = swarm.counts();
+        AnnounceReply {
+            interval: self.interval.as_secs(),
+            complete,
+            incomplete,
+            peers,
+        }
+    }
+
+    fn scrape(&self, hash: &str) -> ScrapeReply {
+        match self.swarms.get(hash) {
+            Some(swarm) => {
+                let (complete, incomplete) = swarm.counts();
+                ScrapeReply {
+                    complete,
+                    incomplete,
+                    downloaded: swarm.downloaded,
+                }
+            },
+            None => ScrapeReply::default(),
+        }
+    }
+
+    // Forget peers that went away without saying so
+    fn expire(&mut self) {
+        let timeout = self.interval * 2;
+        for swarm in self.swarms.values_mut() {
+            swarm.peers.retain(|_, p| p.last_seen.elapsed() < timeout);
+        }
+        self.swarms.retain(|_, s| !s.peers.is_empty() || s.downloaded > 0);
+        self.sources.retain(|_, (since, _)| since.elapsed() < SOURCE_WINDOW);
+    }
+}
+
+// Answer announces and scrapes handed over from
+// the UDP socket until shutting down
+pub async fn serve(
+    receiver: &mut Receiver<Packet>,
+    udp: Arc<UdpSocket>,
+    interval: Duration,
+    shutdown: CancellationToken
+) {
+    let mut tracker = Tracker::new(interval);
+    let mut expire = tokio::time::interval(interval);
+    info!("[TRACKER] Tracking peers, announce interval {}s", interval.as_secs());
+
+    loop {
+        let packet = tokio::select! {
+            Some(packet) = receiver.recv() => packet,
+            _ = expire.tick() => {
+                tracker.expire();
+                continue;
+            },
+            _ = shutdown.cancelled() => break,
+            else => break,
+        };
+
+        let from: SocketAddr = match packet.from_ip.parse() {
+            Ok(a) => a,
+            Err(_) => continue,
+        };
+        if !tracker.allow_reply(from.ip()) {
+            debug!("[TRACKER] Too many requests from {}, dropping", from.ip());
+            continue;
+        }
+
+        let (reply_type, content) = match packet.packet_type {
+            PacketType::Announce => {
+                let request: AnnounceRequest = match serde_json::from_slice(&packet.content) {
+                    Ok(r) => r,
+                    Err(_) => continue,
+                };
+                let reply = tracker.announce(&packet.info_hash, from, request);
+                (PacketType::AnnounceReply, serde_json::to_vec(&reply))
+            },
+            PacketType::Scrape => {
+                (PacketType::ScrapeReply, serde_json::to_vec(&tracker.scrape(&packet.info_hash)))
+            },
+            _ => continue,
+        };
+        let content = match content {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+
+        let reply = Packet {
+            packet_type: reply_type,
+            dest_ip: packet.from_ip.clone(),
+            from_ip: String::new(),
+            info_hash: packet.info_hash,
+            content,
+        };
+        if let Ok(bytes) = encode_datagram(reply) {
+            let _ = udp.send_to(&bytes, from).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn reply(from: &str, interval: u64) -> Packet {
+        let reply = AnnounceReply { interval, complete: 0, incomplete: 0, peers: Vec::new() };
+        Packet {
+            packet_type: PacketType::AnnounceReply,
+            dest_ip: String::new(),
+            from_ip: from.to_string(),
+            info_hash: HASH.to_string(),
+            content: serde_json::to_vec(&reply).unwrap(),
+        }
+    }
+
+    fn request(peer_id: &str) -> AnnounceRequest {
+        AnnounceRequest {
+            peer_id: peer_id.to_string(),
+            port: 8080,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1,
+            event: Event::Started,
+            num_want: MAX_REPLY_PEERS,
+        }
+    }
+
+    #[test]
+    fn only_trusts_its_trackers() {
+        let mut announcer = Announcer::new(&["10.0.0.1".to_string()], HASH, 8080);
+        announcer.resolved.insert("10.0.0.1:8081".to_string());
+
+        assert!(announcer.handle_reply(&reply("10.0.0.2:8081", 60)).is_none());
+        assert!(announcer.handle_reply(&reply("10.0.0.1:8081", u64::MAX)).is_some());
+        assert!(announcer.next() <= Instant::now() + Duration::from_secs(MAX_INTERVAL));
+    }
+
+    #[test]
+    fn caps_swarms_and_peers() {
+        let mut tracker = Tracker::new(Duration::from_secs(60));
+        let from: SocketAddr = "10.0.0.1:8081".parse().unwrap();
+
+        for n in 0..MAX_SWARM_PEERS + 10 {
+            tracker.announce(HASH, from, request(&n.to_string()));
+        }
+        assert_eq!(tracker.swarms[HASH].peers.len(), MAX_SWARM_PEERS);
+
+        for n in 0..MAX_SWARMS + 10 {
+            tracker.announce(&n.to_string(), from, request("a"));
+        }
+        assert_eq!(tracker.swarms.len(), MAX_SWARMS);
+    }
+
+    #[test]
+    fn limits_replies_per_source() {
+        let mut tracker = Tracker::new(Duration::from_secs(60));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+
+        for _ in 0..MAX_SOURCE_REPLIES {
+            assert!(tracker.allow_reply(ip));
+        }
+        assert!(!tracker.allow_reply(ip));
+        assert!(!tracker.allow_reply(mapped));
+        assert!(tracker.allow_reply("10.0.0.2".parse().unwrap()));
+    }
+}
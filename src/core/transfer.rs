@@ -0,0 +1,360 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::rate::RateLimit;
+
+// Pieces are far bigger than a UDP datagram, so over UDP they
+// are split into blocks that are acknowledged one by one.
+// Blocks that aren't acknowledged in time are sent again and
+// the sending window shrinks, much like TCP does.
+pub const BLOCK_SIZE: u64 = 16384;
+
+// Congestion window limits, in blocks
+const INITIAL_WINDOW: f64 = 4.0;
+const MIN_WINDOW: f64 = 1.0;
+const MAX_WINDOW: f64 = 256.0;
+
+// Retransmission timeout limits
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(8);
+
+// Give up on a block after this many retransmissions.
+// The downloader will ask for the whole piece again.
+const MAX_RETRIES: u32 = 8;
+
+// Pieces being reassembled at once, and how long
+// an incomplete piece is kept around
+const MAX_PARTIAL_PIECES: usize = 64;
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How a peer wants its pieces delivered
+#[derive(Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Transport {
+    #[default]
+    Udp,    // Acknowledged blocks over UDP
+    Tcp,    // Whole piece over the TCP connection
+}
+
+pub fn block_count(piece_size: u64) -> u32 {
+    std::cmp::max(1, piece_size.div_ceil(BLOCK_SIZE)) as u32
+}
+
+#[derive(Clone)]
+pub struct Block {
+    pub piece: u64,
+    pub index: u32,
+    pub count: u32,
+    // Torrent the piece belongs to
+    pub info_hash: String,
+    pub data: Vec<u8>,
+}
+
+// Split a piece into the blocks that carry it
+pub fn split_piece(piece: u64, info_hash: &str, data: &[u8]) -> Vec<Block> {
+    let count = block_count(data.len() as u64);
+
+    (0..count)
+        .map(|index| {
+            let start = (index as u64 * BLOCK_SIZE) as usize;
+            let end = std::cmp::min(data.len(), start + BLOCK_SIZE as usize);
+
+            Block {
+                piece,
+                index,
+                count,
+                info_hash: info_hash.to_string(),
+                data: data[start..end].to_vec(),
+            }
+        })
+        .collect()
+}
+
+struct InFlight {
+    block: Block,
+    sent_at: Instant,
+    retries: u32,
+}
+
+// Sends blocks to a single peer, keeping no more than the
+// congestion window unacknowledged at once
+pub struct BlockSender {
+    queue: VecDeque<(Block, u32)>,
+    in_flight: HashMap<(u64, u32), InFlight>,
+    window: f64,
+    threshold: f64,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for BlockSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockSender {
+    pub fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            window: INITIAL_WINDOW,
+            threshold: MAX_WINDOW,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    pub fn push(&mut self, blocks: Vec<Block>) {
+        for block in blocks {
+            self.queue.push_back((block, 0));
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+
+    // Standard smoothed round trip estimate (RFC 6298)
+    fn sample_rtt(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            },
+            Some(srtt) => {
+                let diff = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + diff) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+
+        let rto = srtt + self.rttvar * 4;
+        self.rto = rto.clamp(MIN_RTO, MAX_RTO);
+    }
+
+    pub fn ack(&mut self, piece: u64, index: u32) {
+        let sent = match self.in_flight.remove(&(piece, index)) {
+            Some(s) => s,
+            None => return,
+        };
+
+        // Only time blocks that were sent once, otherwise
+        // we can't tell which copy is being acknowledged
+        if sent.retries == 0 {
+            self.sample_rtt(sent.sent_at.elapsed());
+        }
+
+        // Slow start, then additive increase
+        if self.window < self.threshold {
+            self.window += 1.0;
+        }
+        else {
+            self.window += 1.0 / self.window;
+        }
+        self.window = self.window.min(MAX_WINDOW);
+    }
+
+    // Blocks that should go out right now: retransmissions of
+    // anything that timed out, then new blocks while the
+    // window and the rate limit have room
+    pub fn poll(&mut self, limit: &RateLimit) -> Vec<Block> {
+        let expired: Vec<(u64, u32)> = self.in_flight
+            .iter()
+            .filter(|(_, f)| f.sent_at.elapsed() >= self.rto)
+            .map(|(key, _)| *key)
+            .collect();
+
+        // Loss means the path is congested, so back off
+        // once for this round rather than once per block
+        if !expired.is_empty() {
+            self.threshold = (self.window / 2.0).max(MIN_WINDOW * 2.0);
+            self.window = (self.window / 2.0).max(MIN_WINDOW);
+            self.rto = (self.rto * 2).min(MAX_RTO);
+        }
+
+        for key in expired {
+            if let Some(sent) = self.in_flight.remove(&key) && sent.retries < MAX_RETRIES {
+                self.queue.push_front((sent.block, sent.retries + 1));
+            }
+        }
+
+        let mut ready: Vec<Block> = Vec::new();
+        while (self.in_flight.len() as f64) < self.window.floor() {
+            let size = match self.queue.front() {
+                Some((b, _)) => b.data.len() as u64,
+                None => break,
+            };
+            if !limit.try_take(size) {
+                break;
+            }
+
+            let (block, retries) = match self.queue.pop_front() {
+                Some(b) => b,
+                None => break,
+            };
+
+            self.in_flight.insert((block.piece, block.index), InFlight {
+                block: block.clone(),
+                sent_at: Instant::now(),
+                retries,
+            });
+            ready.push(block);
+        }
+
+        ready
+    }
+}
+
+struct PartialPiece {
+    blocks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    started: Instant,
+}
+
+// Puts pieces back together from their blocks
+#[derive(Default)]
+pub struct PieceAssembler {
+    pieces: HashMap<u64, PartialPiece>,
+}
+
+impl PieceAssembler {
+    pub fn new() -> Self {
+        Self {
+            pieces: HashMap::new(),
+        }
+    }
+
+    // Add a block. Returns the whole piece once every block
+    // has arrived. Blocks that don't match what we expect
+    // for the piece are dropped.
+    pub fn add_block(&mut self, block: Block, expected_count: u32) -> Option<Vec<u8>> {
+        if block.count != expected_count || block.index >= block.count {
+            return None;
+        }
+
+        // Don't let old or bogus pieces pile up
+        self.pieces.retain(|_, p| p.started.elapsed() < PARTIAL_TIMEOUT);
+        if !self.pieces.contains_key(&block.piece) && self.pieces.len() >= MAX_PARTIAL_PIECES {
+            return None;
+        }
+
+        let partial = self.pieces.entry(block.piece).or_insert_with(|| PartialPiece {
+            blocks: vec![None; expected_count as usize],
+            received: 0,
+            started: Instant::now(),
+        });
+
+        let slot = &mut partial.blocks[block.index as usize];
+        if slot.is_none() {
+            *slot = Some(block.data);
+            partial.received += 1;
+        }
+
+        if partial.received < expected_count {
+            return None;
+        }
+
+        let partial = self.pieces.remove(&block.piece)?;
+        Some(partial.blocks.into_iter().flatten().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(piece: u64, size: usize) -> Vec<Block> {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        split_piece(piece, "00", &data)
+    }
+
+    #[test]
+    fn splits_pieces_into_blocks() {
+        let blocks = blocks(3, BLOCK_SIZE as usize * 2 + 1);
+
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks.iter().all(|b| b.piece == 3 && b.count == 3));
+        assert_eq!(blocks[2].data.len(), 1);
+        assert_eq!(block_count(0), 1);
+    }
+
+    #[test]
+    fn sends_within_window() {
+        let mut sender = BlockSender::new();
+        sender.push(blocks(0, BLOCK_SIZE as usize * 10));
+
+        let first = sender.poll(&RateLimit::default());
+        assert_eq!(first.len(), INITIAL_WINDOW as usize);
+        assert!(sender.poll(&RateLimit::default()).is_empty());
+
+        // Every ack opens the window by one in slow start
+        sender.ack(0, first[0].index);
+        assert_eq!(sender.poll(&RateLimit::default()).len(), 2);
+    }
+
+    #[test]
+    fn retransmits_unacked_blocks() {
+        let mut sender = BlockSender::new();
+        sender.push(blocks(0, BLOCK_SIZE as usize * 2));
+
+        let sent = sender.poll(&RateLimit::default());
+        assert_eq!(sent.len(), 2);
+        sender.ack(0, 0);
+
+        // Block 1 times out and goes out again, with a smaller window
+        sender.rto = Duration::ZERO;
+        let window = sender.window;
+        let resent = sender.poll(&RateLimit::default());
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].index, 1);
+        assert!(sender.window < window);
+
+        sender.ack(0, 1);
+        assert!(sender.is_idle());
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut sender = BlockSender::new();
+        sender.push(blocks(0, 1));
+
+        for _ in 0..=MAX_RETRIES {
+            sender.rto = Duration::ZERO;
+            assert_eq!(sender.poll(&RateLimit::default()).len(), 1);
+        }
+        sender.rto = Duration::ZERO;
+        assert!(sender.poll(&RateLimit::default()).is_empty());
+        assert!(sender.is_idle());
+    }
+
+    #[test]
+    fn reassembles_in_any_order() {
+        let blocks = blocks(5, BLOCK_SIZE as usize * 2 + 10);
+        let expected: Vec<u8> = blocks.iter().flat_map(|b| b.data.clone()).collect();
+        let mut assembler = PieceAssembler::new();
+
+        assert!(assembler.add_block(blocks[2].clone(), 3).is_none());
+        assert!(assembler.add_block(blocks[0].clone(), 3).is_none());
+        // Duplicates don't count twice
+        assert!(assembler.add_block(blocks[0].clone(), 3).is_none());
+        assert_eq!(assembler.add_block(blocks[1].clone(), 3), Some(expected));
+    }
+
+    #[test]
+    fn drops_blocks_that_dont_fit() {
+        let blocks = blocks(0, BLOCK_SIZE as usize * 2);
+        let mut assembler = PieceAssembler::new();
+
+        assert!(assembler.add_block(blocks[0].clone(), 3).is_none());
+        let mut bogus = blocks[1].clone();
+        bogus.index = 2;
+        assert!(assembler.add_block(bogus, 2).is_none());
+        assert!(assembler.pieces.is_empty());
+    }
+}
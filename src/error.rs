@@ -0,0 +1,64 @@
+use std::fmt;
+use std::io;
+
+use tokio::sync::mpsc::error::SendError;
+
+// Everything that can go wrong, sorted by who's to blame.
+// A bad peer or torrent should only ever stop itself,
+// never the whole node.
+#[derive(Debug)]
+pub enum Error {
+    // Files and sockets
+    Io(io::Error),
+    // Torrent files, configs and resume state we can't make sense of
+    Parse(String),
+    // A peer sent something it shouldn't have
+    Protocol(String),
+    // A peer went away or never answered
+    Peer(String),
+    // Data that doesn't match its hash
+    Integrity(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Parse(msg) => write!(f, "{}", msg),
+            Error::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Peer(msg) => write!(f, "{}", msg),
+            Error::Integrity(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+
+// The other end of a channel only goes away when
+// the node is shutting down
+impl<T> From<SendError<T>> for Error {
+    fn from(_: SendError<T>) -> Self {
+        Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "Node is shutting down"))
+    }
+}
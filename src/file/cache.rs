@@ -0,0 +1,63 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+// Small least recently used cache. Lookups are linear in the
+// number of entries, which is fine for the handful of file
+// handles and pieces we keep around.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, V>,
+    // Most recently used key at the back
+    order: VecDeque<K>,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        let pos = self.order.iter().position(|k| k == key);
+        if let Some(key) = pos.and_then(|pos| self.order.remove(pos)) {
+            self.order.push_back(key);
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if self.entries.contains_key(key) {
+            self.touch(key);
+        }
+
+        self.entries.get_mut(key)
+    }
+
+    // Insert a value, evicting the least recently
+    // used entry if the cache is full
+    pub fn put(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.insert(key.clone(), value).is_some() {
+            self.touch(&key);
+            return;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > self.capacity && let Some(oldest) = self.order.pop_front() {
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            self.order.remove(pos);
+        }
+
+        self.entries.remove(key)
+    }
+}
@@ -0,0 +1,435 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::core::address::{DEFAULT_TCP_PORT, DEFAULT_UDP_PORT};
+use crate::error::Error;
+
+pub const CONFIG_PATH: &str = "./config.json";
+
+// Bumped whenever the layout of the file changes.
+// Older files are upgraded when they are loaded.
+//
+// 1: downloads and uploads as lists of torrent paths,
+//    network settings at the top level
+// 2: everything below
+pub const CONFIG_VERSION: u64 = 2;
+
+// Every field has a default, so a config only needs the
+// settings that differ. Unknown fields are rejected so
+// typos don't go unnoticed.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub version: u64,
+    // Where downloads are saved, and seeded torrents are
+    // read from, unless a torrent says otherwise
+    pub download_dir: String,
+    pub network: NetworkConfig,
+    pub limits: LimitConfig,
+    pub logging: LogConfig,
+    pub control: ControlConfig,
+    pub tracker: TrackerConfig,
+    pub dht: DhtConfig,
+    pub lsd: LsdConfig,
+    pub downloads: Vec<TorrentConfig>,
+    pub uploads: Vec<TorrentConfig>,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    // 0.0.0.0 for every IPv4 interface, :: for IPv6 as well
+    pub listen_address: IpAddr,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+}
+
+// Rates are in bytes per second, 0 meaning unlimited
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    pub max_upload_rate: u64,
+    pub max_download_rate: u64,
+    // Peers downloaded from at once, per torrent
+    pub max_peers: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    // Also append everything logged to this file
+    pub file: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    pub enabled: bool,
+    // Unix socket a running node takes commands on
+    pub socket: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DhtConfig {
+    pub enabled: bool,
+    // Nodes to join the DHT through, as host:port with
+    // the UDP port. Without any we only wait to be found.
+    pub bootstrap: Vec<String>,
+}
+
+// Local service discovery, finding peers on the LAN
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LsdConfig {
+    pub enabled: bool,
+    // Interface to multicast on, 0.0.0.0 for the default one
+    pub interface: Ipv4Addr,
+}
+
+// Only used when running the tracker command
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackerConfig {
+    // Seconds peers are asked to wait between announces
+    pub interval: u64,
+}
+
+// A single torrent to download or upload. Anything left
+// out falls back to the settings above. Rate limits here
+// only ever lower the global ones.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TorrentConfig {
+    pub torrent: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_upload_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_download_rate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_peers: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            download_dir: "./downloads".to_string(),
+            network: NetworkConfig::default(),
+            limits: LimitConfig::default(),
+            logging: LogConfig::default(),
+            control: ControlConfig::default(),
+            tracker: TrackerConfig::default(),
+            dht: DhtConfig::default(),
+            lsd: LsdConfig::default(),
+            downloads: Vec::new(),
+            uploads: Vec::new(),
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            tcp_port: DEFAULT_TCP_PORT,
+            udp_port: DEFAULT_UDP_PORT,
+        }
+    }
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_upload_rate: 0,
+            max_download_rate: 0,
+            max_peers: 32,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            file: None,
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: "./baconnet.sock".to_string(),
+        }
+    }
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bootstrap: Vec::new(),
+        }
+    }
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interface: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            interval: 300,
+        }
+    }
+}
+
+impl NetworkConfig {
+    // --listen <address>, --tcp-port <port> and --udp-port <port>
+    pub fn apply_flags(&mut self, flags: &[String]) -> Result<(), String> {
+        let mut flags = flags.iter();
+        while let Some(flag) = flags.next() {
+            let value = flags.next().ok_or(format!("Missing value for {}", flag))?;
+            let invalid = || format!("Invalid value for {}: {}", flag, value);
+
+            match flag.as_str() {
+                "--listen" => self.listen_address = value.parse().map_err(|_| invalid())?,
+                "--tcp-port" => self.tcp_port = value.parse().map_err(|_| invalid())?,
+                "--udp-port" => self.udp_port = value.parse().map_err(|_| invalid())?,
+                _ => Err(format!("Unknown flag {}", flag))?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    // Load the config, creating it if it doesn't exist
+    // yet and upgrading it if it was written by an older
+    // version. The old file is kept next to it as .bak.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            let config = Config::default();
+            config.save(path)?;
+            return Ok(config);
+        }
+
+        let text = fs::read_to_string(path)?;
+        let mut json: Value = serde_json::from_str(&text)
+            .map_err(|e| Error::Parse(format!("{}: {}", path.display(), e)))?;
+
+        let upgraded = migrate(&mut json).map_err(Error::Parse)?;
+
+        let config: Config = serde_json::from_value(json)
+            .map_err(|e| Error::Parse(format!("{}: {}", path.display(), e)))?;
+        config.validate().map_err(Error::Parse)?;
+
+        if upgraded {
+            fs::write(path.with_extension("json.bak"), text)?;
+            config.save(path)?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut file = File::create(path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    // Check everything serde can't, listing every
+    // problem at once rather than just the first
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.download_dir.trim().is_empty() {
+            problems.push("download_dir can't be empty".to_string());
+        }
+        if self.network.tcp_port == 0 {
+            problems.push("network.tcp_port can't be 0".to_string());
+        }
+        if self.network.udp_port == 0 {
+            problems.push("network.udp_port can't be 0".to_string());
+        }
+        if self.limits.max_peers == 0 {
+            problems.push("limits.max_peers must be at least 1".to_string());
+        }
+        if let Some(file) = &self.logging.file && file.trim().is_empty() {
+            problems.push("logging.file can't be empty, leave it out instead".to_string());
+        }
+        if self.control.enabled && self.control.socket.trim().is_empty() {
+            problems.push("control.socket can't be empty while control is enabled".to_string());
+        }
+        if self.tracker.interval == 0 {
+            problems.push("tracker.interval must be at least 1".to_string());
+        }
+        for (i, node) in self.dht.bootstrap.iter().enumerate() {
+            if node.trim().is_empty() {
+                problems.push(format!("dht.bootstrap[{}] can't be empty", i));
+            }
+        }
+
+        let lists = [("downloads", &self.downloads), ("uploads", &self.uploads)];
+        for (name, list) in lists {
+            for (i, entry) in list.iter().enumerate() {
+                if entry.torrent.trim().is_empty() {
+                    problems.push(format!("{}[{}].torrent can't be empty", name, i));
+                }
+                if entry.max_peers == Some(0) {
+                    problems.push(format!("{}[{}].max_peers must be at least 1", name, i));
+                }
+                if let Some(dir) = &entry.download_dir && dir.trim().is_empty() {
+                    problems.push(format!("{}[{}].download_dir can't be empty", name, i));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let mut message = String::from("Invalid config:");
+        for problem in problems {
+            message.push_str("\n  - ");
+            message.push_str(&problem);
+        }
+
+        Err(message)
+    }
+
+    pub fn download_dir(&self, entry: &TorrentConfig) -> PathBuf {
+        PathBuf::from(entry.download_dir.as_ref().unwrap_or(&self.download_dir))
+    }
+
+    pub fn max_peers(&self, entry: &TorrentConfig) -> usize {
+        entry.max_peers.unwrap_or(self.limits.max_peers)
+    }
+}
+
+// Bring an older config up to the current version.
+// Returns whether anything had to change.
+fn migrate(json: &mut Value) -> Result<bool, String> {
+    let config = json.as_object_mut().ok_or("Config must be a JSON object")?;
+
+    let version = match config.get("version") {
+        None => 1,
+        Some(v) => v.as_u64().ok_or("version must be a number")?,
+    };
+    if version > CONFIG_VERSION {
+        Err(format!("Config version {} is newer than this build supports ({})",
+            version, CONFIG_VERSION))?
+    }
+    if version == CONFIG_VERSION {
+        return Ok(false);
+    }
+
+    if version < 2 {
+        upgrade_v1(config);
+    }
+
+    config.insert("version".to_string(), json!(CONFIG_VERSION));
+    // Not on stdout, which --json output has to itself
+    eprintln!("[CONFIG] Upgraded config from version {} to {}", version, CONFIG_VERSION);
+
+    Ok(true)
+}
+
+fn upgrade_v1(config: &mut Map<String, Value>) {
+    // Torrents were plain paths
+    for list in ["downloads", "uploads"] {
+        if let Some(Value::Array(entries)) = config.get_mut(list) {
+            for entry in entries.iter_mut() {
+                if let Value::String(path) = entry {
+                    *entry = json!({ "torrent": path });
+                }
+            }
+        }
+    }
+
+    // Network settings lived at the top level
+    let mut network = Map::new();
+    for key in ["listen_address", "tcp_port", "udp_port"] {
+        if let Some(value) = config.remove(key) {
+            network.insert(key.to_string(), value);
+        }
+    }
+    if !network.is_empty() {
+        config.insert("network".to_string(), Value::Object(network));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_v1() {
+        let mut json = json!({
+            "download_dir": "./files",
+            "listen_address": "127.0.0.1",
+            "tcp_port": 9000,
+            "udp_port": 9001,
+            "downloads": ["a.json"],
+            "uploads": ["b.json", { "torrent": "c.json" }],
+        });
+
+        assert!(migrate(&mut json).unwrap());
+        let config: Config = serde_json::from_value(json).unwrap();
+
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.download_dir, "./files");
+        assert_eq!(config.network.listen_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(config.network.tcp_port, 9000);
+        assert_eq!(config.network.udp_port, 9001);
+        assert_eq!(config.downloads.len(), 1);
+        assert_eq!(config.downloads[0].torrent, "a.json");
+        let uploads: Vec<&str> = config.uploads.iter().map(|e| e.torrent.as_str()).collect();
+        assert_eq!(uploads, vec!["b.json", "c.json"]);
+    }
+
+    #[test]
+    fn leaves_current_version_alone() {
+        let mut json = serde_json::to_value(Config::default()).unwrap();
+        let before = json.clone();
+
+        assert!(!migrate(&mut json).unwrap());
+        assert_eq!(json, before);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut json = json!({ "version": CONFIG_VERSION + 1 });
+        assert!(migrate(&mut json).is_err());
+
+        let mut json = json!({ "version": "2" });
+        assert!(migrate(&mut json).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::core::structs::TorrentInfo;
+use crate::error::{Error, Result};
+use crate::file::torrent::{from_hex, info_hash};
+
+// Magnet style links, for sharing a torrent without its file:
+//
+//   magnet:?xt=urn:baconnet:<info hash>&dn=<name>&tr=<tracker>&x.pe=<peer>
+//
+// Only the info hash is required. Everything else in the
+// torrent is fetched from peers when the download starts.
+
+const PREFIX: &str = "magnet:?";
+const URN: &str = "urn:baconnet:";
+
+// Fetched torrents are kept in a hidden directory inside
+// the download root, so a restart doesn't fetch them again
+const METADATA_DIR: &str = ".torrents";
+
+pub struct MagnetLink {
+    pub info_hash: String,
+    // Display name, until the real one is known
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Peers that should have the torrent
+    pub peers: Vec<String>,
+}
+
+pub fn is_magnet(torrent: &str) -> bool {
+    torrent.starts_with(PREFIX)
+}
+
+impl MagnetLink {
+    pub fn from_info(info: &TorrentInfo) -> Self {
+        Self {
+            info_hash: info_hash(info),
+            name: Some(info.filename.clone()),
+            trackers: info.trackers.clone(),
+            // Host names aren't allowed in links
+            peers: info.peers.iter()
+                .filter(|p| p.parse::<SocketAddr>().is_ok())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn parse(link: &str) -> Result<Self> {
+        let query = link.strip_prefix(PREFIX)
+            .ok_or(Error::Parse(format!("Not a magnet link: {}", link)))?;
+
+        let mut info_hash: Option<String> = None;
+        let mut name: Option<String> = None;
+        let mut trackers: Vec<String> = Vec::new();
+        let mut peers: Vec<String> = Vec::new();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = decode(value)
+                .ok_or(Error::Parse(format!("Bad escape in magnet link: {}", pair)))?;
+
+            // Unknown keys are left for whoever added them
+            match key {
+                "xt" => info_hash = value.strip_prefix(URN).map(|h| h.to_lowercase()),
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => {
+                    if value.parse::<SocketAddr>().is_err() {
+                        return Err(Error::Parse(format!("Invalid peer address {}", value)));
+                    }
+                    peers.push(value)
+                },
+                _ => (),
+            }
+        }
+
+        let info_hash = info_hash
+            .ok_or(Error::Parse("Magnet link has no info hash".to_string()))?;
+        if info_hash.len() != 40 || from_hex(&info_hash).is_none() {
+            return Err(Error::Parse(format!("Invalid info hash {}", info_hash)));
+        }
+
+        Ok(Self {
+            info_hash,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    // Name to show until the torrent is fetched
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.info_hash)
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}xt={}{}", PREFIX, URN, self.info_hash)?;
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", encode(name))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", encode(tracker))?;
+        }
+        for peer in &self.peers {
+            write!(f, "&x.pe={}", encode(peer))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Percent encode everything but what's safe in a query value
+fn encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+            | b'-' | b'.' | b'_' | b'~' | b':' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+fn decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                // from_str_radix would also take a sign
+                let hex = bytes.get(i + 1..i + 3)?;
+                if !hex.iter().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                i += 3;
+            },
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+pub fn metadata_path(root: &Path, info_hash: &str) -> PathBuf {
+    root.join(METADATA_DIR).join(format!("{}.json", info_hash))
+}
+
+// Keep a fetched torrent so the download can
+// be picked back up like any other
+pub fn save_metadata(root: &Path, info: &TorrentInfo) -> Result<PathBuf> {
+    let path = metadata_path(root, &info_hash(info));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(&path)?;
+    file.write_all(serde_json::to_string_pretty(info)?.as_bytes())?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn parses_full_link() {
+        let link = format!(
+            "magnet:?xt=urn:baconnet:{}&dn=my%20file&tr=tracker.lan:6969&x.pe=127.0.0.1:9700&x.pe=[::1]:9700",
+            HASH.to_uppercase()
+        );
+        let magnet = MagnetLink::parse(&link).unwrap();
+
+        assert_eq!(magnet.info_hash, HASH);
+        assert_eq!(magnet.name.as_deref(), Some("my file"));
+        assert_eq!(magnet.trackers, vec!["tracker.lan:6969"]);
+        assert_eq!(magnet.peers, vec!["127.0.0.1:9700", "[::1]:9700"]);
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let magnet = MagnetLink {
+            info_hash: HASH.to_string(),
+            name: Some("a & b".to_string()),
+            trackers: vec!["10.0.0.1:6969".to_string()],
+            peers: vec!["10.0.0.2:8080".to_string()],
+        };
+        let parsed = MagnetLink::parse(&magnet.to_string()).unwrap();
+
+        assert_eq!(parsed.info_hash, magnet.info_hash);
+        assert_eq!(parsed.name, magnet.name);
+        assert_eq!(parsed.trackers, magnet.trackers);
+        assert_eq!(parsed.peers, magnet.peers);
+    }
+
+    #[test]
+    fn only_needs_info_hash() {
+        let magnet = MagnetLink::parse(&format!("magnet:?xt=urn:baconnet:{}", HASH)).unwrap();
+
+        assert_eq!(magnet.display_name(), HASH);
+        assert!(magnet.peers.is_empty());
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_links() {
+        let bad = [
+            format!("https://example.com/?xt=urn:baconnet:{}", HASH),
+            "magnet:?dn=name".to_string(),
+            "magnet:?xt=urn:baconnet:1234".to_string(),
+            format!("magnet:?xt=urn:btih:{}", HASH),
+            format!("magnet:?xt=urn:baconnet:{}&dn=%zz", HASH),
+            format!("magnet:?xt=urn:baconnet:{}&dn=%+1", HASH),
+            format!("magnet:?xt=urn:baconnet:{}&x.pe=not-an-address", HASH),
+            format!("magnet:?xt=urn:baconnet:{}&x.pe=127.0.0.1", HASH),
+        ];
+
+        for link in bad {
+            assert!(MagnetLink::parse(&link).is_err(), "accepted {}", link);
+        }
+    }
+}
@@ -1 +1,6 @@
 pub mod torrent;
+pub mod cache;
+pub mod storage;
+pub mod resume;
+pub mod magnet;
+pub mod config;
@@ -0,0 +1,143 @@
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::bitfield::Bitfield;
+use crate::core::structs::TorrentInfo;
+use crate::error::Result;
+use crate::file::storage::Storage;
+use crate::file::torrent::{from_hex, hash_piece, info_hash, recheck_pieces, to_hex};
+use crate::info;
+
+// Resume files live in a hidden directory
+// inside the download root
+const RESUME_DIR: &str = ".resume";
+
+// Everything needed to pick a download back up
+// after the process has stopped
+#[derive(Serialize, Deserialize)]
+pub struct ResumeState {
+    pub info_hash: String,
+    // Where the torrent is being downloaded to
+    pub target: String,
+    pub piece_count: u64,
+    // Completed pieces as a hex encoded bitfield
+    pub have: String,
+    // File modification times when the state was saved
+    pub mtimes: Vec<u64>,
+}
+
+pub fn resume_path(root: &Path, info: &TorrentInfo) -> PathBuf {
+    root.join(RESUME_DIR).join(format!("{}.json", info_hash(info)))
+}
+
+fn target_path(root: &Path, info: &TorrentInfo) -> String {
+    root.join(&info.filename).to_string_lossy().to_string()
+}
+
+pub fn save_resume(
+    root: &Path,
+    info: &TorrentInfo,
+    storage: &Storage,
+    have: &Bitfield
+) -> Result<()> {
+    let state = ResumeState {
+        info_hash: info_hash(info),
+        target: target_path(root, info),
+        piece_count: have.len(),
+        have: to_hex(&have.to_bytes()),
+        mtimes: storage.mtimes(),
+    };
+
+    let path = resume_path(root, info);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so a crash
+    // mid-write never leaves a corrupt resume file
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(serde_json::to_string(&state)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+// Hash whatever is already on disk and write out a fresh
+// resume file describing exactly which pieces are valid
+pub fn recheck(root: &Path, info: &TorrentInfo, storage: &Storage) -> Result<Bitfield> {
+    let valid = recheck_pieces(info, storage);
+    save_resume(root, info, storage, &valid)?;
+
+    Ok(valid)
+}
+
+// Saved pieces and file modification times,
+// if the resume file matches this torrent
+fn read_state(root: &Path, info: &TorrentInfo) -> Option<(Bitfield, Vec<u64>)> {
+    let piece_count = info.piece_count();
+
+    let file = File::open(resume_path(root, info)).ok()?;
+    let state: ResumeState = serde_json::from_reader(BufReader::new(file)).ok()?;
+
+    // Make sure the state belongs to this torrent and location
+    if state.info_hash != info_hash(info)
+        || state.target != target_path(root, info)
+        || state.piece_count != piece_count
+    {
+        return None;
+    }
+
+    let have = from_hex(&state.have).and_then(|b| Bitfield::from_bytes(&b, piece_count))?;
+
+    Some((have, state.mtimes))
+}
+
+// Load the saved piece state for a torrent.
+//
+// If the files haven't been touched since the state was saved
+// it is trusted as is. Otherwise every piece it claims is hashed
+// again and only the ones that still match are kept. Without any
+// usable state, existing data (say a half finished copy) is
+// rechecked from scratch so it doesn't have to be downloaded again.
+pub fn load_resume(root: &Path, info: &TorrentInfo, storage: &Storage) -> Bitfield {
+    let piece_count = info.piece_count();
+
+    let (have, mtimes) = match read_state(root, info) {
+        Some(state) => state,
+        None => {
+            if storage.mtimes().iter().all(|t| *t == 0) {
+                return Bitfield::new(piece_count);
+            }
+
+            info!("[RESUME] Found existing data without resume state, rechecking");
+            return recheck(root, info, storage)
+                .unwrap_or_else(|_| recheck_pieces(info, storage));
+        }
+    };
+
+    if mtimes == storage.mtimes() {
+        return have;
+    }
+
+    info!("[RESUME] Files changed since last run, rechecking {} pieces", have.count());
+    let mut checked = Bitfield::new(piece_count);
+    for location in 0..piece_count {
+        if !have.get(location) {
+            continue;
+        }
+
+        let offset = location * info.piece_length;
+        if let Ok(data) = storage.read_at(offset, info.piece_size(location))
+            && hash_piece(&data) == info.pieces[location as usize]
+        {
+            checked.set(location);
+        }
+    }
+
+    checked
+}
@@ -0,0 +1,268 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::core::structs::TorrentInfo;
+use crate::error::{Error, Result};
+use crate::file::cache::LruCache;
+use crate::file::torrent::flatten_tree;
+
+// Open file handles kept around between reads and writes
+const MAX_OPEN_FILES: usize = 16;
+
+// A file within the torrent's contiguous byte space
+struct StorageFile {
+    path: PathBuf,
+    offset: u64,
+    size: u64,
+}
+
+// Maps the torrent's pieces onto the files of its tree.
+// Pieces are addressed over all files concatenated in
+// tree order, so reads and writes may span several files.
+pub struct Storage {
+    files: Vec<StorageFile>,
+    size: u64,
+    // Keyed by file index and whether the handle is writable
+    handles: Mutex<LruCache<(usize, bool), File>>,
+}
+
+impl Storage {
+    pub fn new(root: &Path, info: &TorrentInfo) -> Result<Self> {
+        let mut files: Vec<StorageFile> = Vec::new();
+        let mut offset: u64 = 0;
+
+        for (path, size) in flatten_tree(&info.files) {
+            // Torrent files come from other people, so never
+            // let a path escape the download root
+            let safe = path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+            if !safe {
+                return Err(Error::Parse(format!("Unsafe path in torrent: {}", path.display())));
+            }
+
+            files.push(StorageFile {
+                path: root.join(path),
+                offset,
+                size,
+            });
+            offset += size;
+        }
+
+        if offset != info.size {
+            return Err(Error::Parse("Torrent file sizes don't add up to torrent size".to_string()));
+        }
+
+        Ok(Self {
+            files,
+            size: offset,
+            handles: Mutex::new(LruCache::new(MAX_OPEN_FILES)),
+        })
+    }
+
+    // Run f on a cached handle for the given file,
+    // opening the file first if it isn't cached yet
+    fn with_handle<T>(
+        &self,
+        index: usize,
+        write: bool,
+        f: impl FnOnce(&mut File) -> std::io::Result<T>
+    ) -> std::io::Result<T> {
+        let mut handles = self.handles.lock().unwrap();
+        let key = (index, write);
+
+        // Take the handle out while it's in use and only put
+        // it back if it worked, so a broken handle isn't reused
+        let mut handle = match handles.remove(&key) {
+            Some(h) => h,
+            None => {
+                let path = &self.files[index].path;
+                match write {
+                    true => OpenOptions::new().write(true).open(path)?,
+                    false => File::open(path)?,
+                }
+            }
+        };
+
+        let result = f(&mut handle);
+        if result.is_ok() {
+            handles.put(key, handle);
+        }
+
+        result
+    }
+
+    // Recreate the directory structure and create
+    // every file at its full size
+    pub fn allocate(&self) -> std::io::Result<()> {
+        for file in &self.files {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // --- WARNING ---
+            // Test this on windows! Sparse files
+            // might only be a Linux thing!
+            let handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&file.path)?;
+            handle.set_len(file.size)?;
+        }
+
+        Ok(())
+    }
+
+    // Last modification time of every file in seconds,
+    // or 0 for files that don't exist yet
+    pub fn mtimes(&self) -> Vec<u64> {
+        self.files
+            .iter()
+            .map(|file| {
+                fs::metadata(&file.path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    // Read len bytes starting at the given offset of
+    // the torrent, gathering them across file boundaries
+    pub fn read_at(&self, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let end = offset + len;
+        if end > self.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Read past end of torrent"
+            ));
+        }
+
+        let mut data: Vec<u8> = Vec::with_capacity(len as usize);
+        for (index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.size;
+            if file_end <= offset || file.offset >= end {
+                continue;
+            }
+
+            let start = std::cmp::max(offset, file.offset);
+            let stop = std::cmp::min(end, file_end);
+
+            // Only ever read the bytes we need
+            self.with_handle(index, false, |handle| {
+                handle.seek(SeekFrom::Start(start - file.offset))?;
+                handle.take(stop - start).read_to_end(&mut data)
+            })?;
+        }
+
+        // Files shorter than the torrent says they are
+        if data.len() as u64 != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "File shorter than expected"
+            ));
+        }
+
+        Ok(data)
+    }
+
+    // Write data starting at the given offset of the
+    // torrent, splitting it across file boundaries
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let end = offset + data.len() as u64;
+        if end > self.size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Write past end of torrent"
+            ));
+        }
+
+        for (index, file) in self.files.iter().enumerate() {
+            let file_end = file.offset + file.size;
+            if file_end <= offset || file.offset >= end {
+                continue;
+            }
+
+            // Overlap between the write and this file
+            let start = std::cmp::max(offset, file.offset);
+            let stop = std::cmp::min(end, file_end);
+            let chunk = &data[(start - offset) as usize..(stop - offset) as usize];
+
+            self.with_handle(index, true, |handle| {
+                handle.seek(SeekFrom::Start(start - file.offset))?;
+                handle.write_all(chunk)
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // dir/a (3 bytes), dir/sub/empty (0 bytes), dir/sub/b (5 bytes)
+    fn torrent(size: u64, first: &str) -> TorrentInfo {
+        serde_json::from_value(serde_json::json!({
+            "filename": "dir",
+            "created_on": "0",
+            "size": size,
+            "peers": [],
+            "piece_length": 4,
+            "pieces": ["00", "00"],
+            "files": {
+                "filename": "dir",
+                "file_type": "directory",
+                "size": size,
+                "children": [
+                    { "filename": first, "file_type": "file", "size": 3 },
+                    {
+                        "filename": "sub",
+                        "file_type": "directory",
+                        "size": 5,
+                        "children": [
+                            { "filename": "empty", "file_type": "file", "size": 0 },
+                            { "filename": "b", "file_type": "file", "size": 5 }
+                        ]
+                    }
+                ]
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn spans_file_boundaries() {
+        let root = std::env::temp_dir().join(format!("baconnet-storage-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let storage = Storage::new(&root, &torrent(8, "a")).unwrap();
+        storage.allocate().unwrap();
+
+        // Second piece covers the end of a and the start of b
+        storage.write_at(0, b"abcd").unwrap();
+        storage.write_at(4, b"efgh").unwrap();
+
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"abc");
+        assert_eq!(fs::read(root.join("dir/sub/empty")).unwrap(), b"");
+        assert_eq!(fs::read(root.join("dir/sub/b")).unwrap(), b"defgh");
+        assert_eq!(storage.read_at(2, 3).unwrap(), b"cde");
+        assert_eq!(storage.read_at(0, 8).unwrap(), b"abcdefgh");
+
+        assert!(storage.read_at(6, 3).is_err());
+        assert!(storage.write_at(7, b"xy").is_err());
+
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rejects_bad_trees() {
+        let root = std::env::temp_dir();
+        assert!(Storage::new(&root, &torrent(8, "..")).is_err());
+        assert!(Storage::new(&root, &torrent(9, "a")).is_err());
+    }
+}
@@ -1,15 +1,18 @@
-use std::env;
 use std::fs::{self, File};
-use std::io::{BufReader, Write};
-use std::error::Error;
-use std::path::Path;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value, Map};
-use sha1::digest::generic_array::GenericArray;
 use sha1::{Sha1, Digest};
 
-#[derive(Default, PartialEq, Serialize, Deserialize)]
+use crate::core::bitfield::Bitfield;
+use crate::core::structs::{TorrentInfo, PIECE_LENGTH};
+use crate::error::{Error, Result};
+use crate::file::storage::Storage;
+
+#[derive(Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum FileType {
     #[default]
     NONE,
@@ -17,96 +20,58 @@ pub enum FileType {
     DIRECTORY,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct FileNode {
     pub filename: String,
     pub file_type: FileType,
     pub size: u64,
+    #[serde(default)]
     pub hash: String,
+    #[serde(default)]
     pub children: Vec<FileNode>,
 }
 
-
-pub fn parse_torrent_file(filename: &str) -> Result<FileNode, Box<dyn Error>> {
+pub fn parse_torrent_file(filename: &str) -> Result<TorrentInfo> {
     // Open JSON file and create BufReader
     let file = File::open(filename)?;
     let reader = BufReader::new(file);
 
-    // Convert to JSON object
-    let json: serde_json::Value = serde_json::from_reader(reader)?;
-
-    // Initialize file tree root
-    let mut tree = FileNode {
-        filename: filename.to_string(),
-        file_type: FileType::NONE,
-        size: 0,
-        hash: String::new(),
-        children: Vec::new()
-    };
+    let info: TorrentInfo = serde_json::from_reader(reader)?;
+    check_torrent(info)
+}
 
-    // Construct file tree
-    for (key, value) in json.as_object().unwrap() {
-        let res = parse_tree(key, &value)?;
-        if res.file_type == FileType::NONE {
-            for child in res.children {
-                tree.children.push(child);
-            }
-        }
-        else {
-            tree.children.push(res);
-        }
-    } 
-    
-    Ok(tree)
+// A torrent fetched from a peer rather than read from disk
+pub fn parse_torrent(bytes: &[u8]) -> Result<TorrentInfo> {
+    check_torrent(serde_json::from_slice(bytes)?)
 }
 
-fn parse_tree(name: &str, obj: &Value) -> Result<FileNode, Box<dyn Error + 'static>> {
-    if let Some(obj_type) = obj["type"].as_str() {
-        match obj_type {
-            "file" => {
-                let file_node = FileNode {
-                    filename: name.to_string(),
-                    file_type: FileType::FILE,
-                    size: obj["size"].as_u64().unwrap(),
-                    hash: String::from(obj["hash"].as_str().unwrap()),
-                    children: Vec::new()    
-                };
-                Ok(file_node)
-            },
-            "directory" => {
-                let mut file_node = FileNode {
-                    filename: name.to_string(),
-                    file_type: FileType::DIRECTORY,
-                    ..Default::default()
-                };
+fn check_torrent(mut info: TorrentInfo) -> Result<TorrentInfo> {
+    // Older torrent files only describe a single file
+    // and have no tree, so build the one node tree here
+    if info.files.file_type == FileType::NONE {
+        info.files = FileNode {
+            filename: info.filename.clone(),
+            file_type: FileType::FILE,
+            size: info.size,
+            ..Default::default()
+        };
+    }
 
-                for (key, value) in obj.as_object().unwrap() {
-                    // Skip key entry.
-                    // Everything else is a file.
-                    if key == "type" {
-                        continue;
-                    }
+    if info.files.filename != info.filename || info.files.size != info.size {
+        return Err(Error::Parse("Torrent file tree doesn't match torrent info".to_string()));
+    }
 
-                    let res = parse_tree(key.as_str(), value);
-                    match res {
-                        Ok(f) => {
-                            file_node.children.push(f);
-                        },
-                        Err(_) => {
-                            // Just ignore for now.
-                            // This shouldn't happen anyways
-                            todo!();
-                        }
-                    }
-                }
-                Ok(file_node)
-            },
-            _ => Ok(FileNode::default()),
-        }
+    // Without a hash for every piece there
+    // is no way to verify what we receive
+    if info.piece_length == 0 {
+        return Err(Error::Parse("Torrent piece_length can't be 0".to_string()));
     }
-    else {
-        Ok(FileNode::default())
+    if info.pieces.len() as u64 != info.piece_count() {
+        return Err(Error::Parse(format!("Torrent has {} piece hashes but {} pieces",
+            info.pieces.len(), info.piece_count())));
     }
+
+    Ok(info)
 }
 
 pub fn print_tree(tree: &FileNode, level: u16) {
@@ -123,21 +88,20 @@ pub fn print_tree(tree: &FileNode, level: u16) {
             }
             println!("{}", tree.filename);
             for child in &tree.children {
-                print_tree(&child, level + 1);
+                print_tree(child, level + 1);
             }
         }
     }
 }
 
-fn get_file_hash(path: &str) -> String {
+fn get_file_hash(path: &str) -> Result<String> {
     let mut hasher = Sha1::new();
     
     let path = path.replace("\"", "");
-    let mut file = fs::File::open(path)
-        .expect("Failed to hash file");
+    let mut file = fs::File::open(path)?;
 
     // Copy file contents
-    let n = std::io::copy(&mut file, &mut hasher).unwrap();
+    std::io::copy(&mut file, &mut hasher)?;
 
     // Hash
     let hash = hasher.finalize();
@@ -145,7 +109,217 @@ fn get_file_hash(path: &str) -> String {
     // Convert to string
     let hash_str = format!("{:x}", hash);
 
-    hash_str
+    Ok(hash_str)
+}
+
+pub fn hash_piece(data: &[u8]) -> String {
+    let hash = Sha1::digest(data);
+    format!("{:x}", hash)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Identifies a torrent by its contents. Only fields that
+// describe the data are hashed, so the same contents give
+// the same hash no matter who made the torrent or which
+// peers it lists. Every field is length prefixed, or fixed
+// size, so no two different torrents encode the same way.
+pub fn info_hash(info: &TorrentInfo) -> String {
+    let mut hasher = Sha1::new();
+
+    hash_field(&mut hasher, info.filename.as_bytes());
+    hasher.update(info.size.to_be_bytes());
+    hasher.update(info.piece_length.to_be_bytes());
+    hasher.update((info.pieces.len() as u64).to_be_bytes());
+    for piece in &info.pieces {
+        hash_field(&mut hasher, piece.as_bytes());
+    }
+    hash_node(&mut hasher, &info.files);
+
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_field(hasher: &mut Sha1, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+// The tree is hashed node by node rather than as paths,
+// so the result is the same on every platform
+fn hash_node(hasher: &mut Sha1, node: &FileNode) {
+    let file_type: u8 = match node.file_type {
+        FileType::NONE => 0,
+        FileType::FILE => 1,
+        FileType::DIRECTORY => 2,
+    };
+
+    hash_field(hasher, node.filename.as_bytes());
+    hasher.update([file_type]);
+    hasher.update(node.size.to_be_bytes());
+    hash_field(hasher, node.hash.as_bytes());
+    hasher.update((node.children.len() as u64).to_be_bytes());
+    for child in &node.children {
+        hash_node(hasher, child);
+    }
+}
+
+// Every file in the tree in piece order, along with its
+// size and its path relative to the torrent's parent directory.
+// The root node's name is always the first path component.
+pub fn flatten_tree(tree: &FileNode) -> Vec<(PathBuf, u64)> {
+    let mut files: Vec<(PathBuf, u64)> = Vec::new();
+    flatten_node(tree, PathBuf::new(), &mut files);
+
+    files
+}
+
+fn flatten_node(node: &FileNode, parent: PathBuf, files: &mut Vec<(PathBuf, u64)>) {
+    let path = parent.join(&node.filename);
+    match node.file_type {
+        FileType::FILE => files.push((path, node.size)),
+        FileType::DIRECTORY => {
+            for child in &node.children {
+                flatten_node(child, path.clone(), files);
+            }
+        },
+        FileType::NONE => (),
+    }
+}
+
+// Hash the contents of the tree one piece at a time. Pieces are
+// taken over all files concatenated in tree order, so a piece
+// can span the end of one file and the start of the next.
+pub fn get_piece_hashes(
+    base: &Path, 
+    tree: &FileNode, 
+    piece_length: u64
+) -> Result<Vec<String>> {
+    let mut hashes: Vec<String> = Vec::new();
+    let mut buf: Vec<u8> = Vec::with_capacity(piece_length as usize);
+
+    for (path, _) in flatten_tree(tree) {
+        let mut file = File::open(base.join(path))?;
+        loop {
+            let remaining = piece_length - buf.len() as u64;
+            let n = (&mut file).take(remaining).read_to_end(&mut buf)?;
+
+            if buf.len() as u64 == piece_length {
+                hashes.push(hash_piece(&buf));
+                buf.clear();
+            }
+            if n == 0 {
+                break;
+            }
+        }
+    }
+
+    // Final short piece
+    if !buf.is_empty() {
+        hashes.push(hash_piece(&buf));
+    }
+
+    Ok(hashes)
+}
+
+// Build the file tree for a file or directory.
+// Children are sorted so the piece order is stable.
+pub fn build_tree(path: &Path) -> Result<FileNode> {
+    let metadata = fs::metadata(path)?;
+    let filename = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return Err(Error::Parse(format!("Invalid path {}", path.display()))),
+    };
+
+    if metadata.is_dir() {
+        let mut node = FileNode {
+            filename,
+            file_type: FileType::DIRECTORY,
+            ..Default::default()
+        };
+
+        for entry in fs::read_dir(path)? {
+            let child = build_tree(&entry?.path())?;
+            node.size += child.size;
+            node.children.push(child);
+        }
+        node.children.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+        Ok(node)
+    }
+    else {
+        let path_str = path.to_string_lossy();
+        Ok(FileNode {
+            filename,
+            file_type: FileType::FILE,
+            size: metadata.len(),
+            hash: get_file_hash(&path_str)?,
+            children: Vec::new(),
+        })
+    }
+}
+
+// Build the torrent description for a file or directory
+pub fn create_torrent_info(
+    path: &str,
+    peers: Vec<String>,
+    trackers: Vec<String>
+) -> Result<TorrentInfo> {
+    let path = Path::new(path);
+    let files = build_tree(path)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let created_on = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        .to_string();
+
+    Ok(TorrentInfo {
+        filename: files.filename.clone(),
+        created_on,
+        size: files.size,
+        peers,
+        piece_length: PIECE_LENGTH,
+        pieces: get_piece_hashes(base, &files, PIECE_LENGTH)?,
+        files,
+        trackers,
+    })
+}
+
+// Hash an existing download piece by piece and work out exactly
+// which pieces are valid. Unlike verify_file_tree, which only says
+// whether the whole tree matches, this lets a partial or modified
+// download be resumed instead of started over.
+pub fn recheck_pieces(info: &TorrentInfo, storage: &Storage) -> Bitfield {
+    let mut valid = Bitfield::new(info.piece_count());
+
+    for location in 0..info.piece_count() {
+        // Missing or short files just mean the piece isn't there yet
+        let offset = location * info.piece_length;
+        let data = match storage.read_at(offset, info.piece_size(location)) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        if hash_piece(&data) == info.pieces[location as usize] {
+            valid.set(location);
+        }
+    }
+
+    valid
 }
 
 pub fn verify_file_tree(tree: &FileNode, path: &str) -> bool {
@@ -155,56 +329,53 @@ pub fn verify_file_tree(tree: &FileNode, path: &str) -> bool {
         return false
     }
 
+    // Single file torrents have no directory to walk
+    if tree.file_type == FileType::FILE {
+        return Path::new(path).is_file() && get_file_hash(path).is_ok_and(|h| h == tree.hash);
+    }
+
     match fs::read_dir(path) {
         Ok(contents) => {
-            for entry in contents {
-                match entry {
-                    Ok(e) => {
-                        let local_path = e.path();
-                        let path_str = local_path
-                            .to_str()        
-                            .unwrap();
-
-                        // Extract filename (last part of the path),
-                        // so either (file.extension) or folder name
-                        let parts: Vec<&str> = path_str
-                            .split("/")
-                            .collect();
-                        let filename = parts.last().unwrap();
-
-                        let is_dir = local_path.is_dir();
-                        
-                        // Check if current tree node contains the file 
-                        let mut node: Option<&FileNode> = None;
-                        for child in &tree.children {
-                            if child.filename == *filename {
-                                if !is_dir {
-                                    let hash = get_file_hash(path_str);
-                                    
-                                    if child.hash != hash {
-                                        return false
-                                    }
-                                }
-                                node = Some(child);
-                                break
+            for e in contents.flatten() {
+                let local_path = e.path();
+                let path_str = local_path.to_string_lossy();
+
+                // Either (file.extension) or folder name
+                let filename = e.file_name().to_string_lossy().to_string();
+
+                let is_dir = local_path.is_dir();
+                
+                // Check if current tree node contains the file 
+                let mut node: Option<&FileNode> = None;
+                for child in &tree.children {
+                    if child.filename == filename {
+                        if !is_dir {
+                            // Unreadable counts as a mismatch
+                            let matches = get_file_hash(&path_str)
+                                .is_ok_and(|hash| hash == child.hash);
+                            
+                            if !matches {
+                                return false
                             }
                         }
-                        if node.is_none() {
-                            return false
-                        }
+                        node = Some(child);
+                        break
+                    }
+                }
+                let node = match node {
+                    Some(n) => n,
+                    None => return false,
+                };
 
-                        // Print
-                        //println!("{}", path_str);
-                        
-                        // Recurse if necessary
-                        if is_dir  {
-                            match verify_file_tree(node.unwrap(), path_str) {
-                                false => return false,
-                                true => continue
-                            }
-                        }
+                // Print
+                //println!("{}", path_str);
+                
+                // Recurse if necessary
+                if is_dir  {
+                    match verify_file_tree(node, &path_str) {
+                        false => return false,
+                        true => continue
                     }
-                    Err(_) => ()
                 }
             }
         },
@@ -216,75 +387,131 @@ pub fn verify_file_tree(tree: &FileNode, path: &str) -> bool {
     true 
 }
 
-pub fn create_torrent_file(path: &str) -> Result<(), Box<dyn Error>> {
+// Returns the path of the torrent file written
+pub fn create_torrent_file(path: &str, peers: Vec<String>, trackers: Vec<String>) -> Result<String> {
     if Path::new(path).exists() {
-        // Gather directory details
-        let parts: Vec<&str> = path
-            .split("/")
-            .collect();
-        let name = parts.last().unwrap();
+        let info = create_torrent_info(path, peers, trackers)?;
 
         // Create JSON file
-        let filename = format!("./torrents/{}.json", name);
-        let mut file = File::create(filename)?;
-   
-        // Get JSON description of directory
-        let json = dir_to_json(path)?;
+        fs::create_dir_all("./torrents")?;
+        let filename = format!("./torrents/{}.json", info.filename);
+        let mut file = File::create(&filename)?;
 
         // Write JSON data to file
-        let data = serde_json::to_string_pretty(&json)?;
+        let data = serde_json::to_string_pretty(&info)?;
         let data = data.as_bytes();
         file.write_all(data)?;
 
-        Ok(())
+        Ok(filename)
     }
     else {
-        Err("Directory doesn't exist")?
+        Err(Error::Parse(format!("Path {} doesn't exist", path)))
     }
 }
 
-pub fn dir_to_json(path: &str) -> Result<Value, Box<dyn Error>> {
-    let mut return_data: Map<String, Value> = Map::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_json(piece_length: u64, pieces: &[&str]) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "filename": "a.bin",
+            "created_on": "0",
+            "size": 10,
+            "peers": [],
+            "piece_length": piece_length,
+            "pieces": pieces,
+        })).unwrap()
+    }
 
-    let entries = fs::read_dir(path)?;
-    for entry in entries {
-        let entry = entry.unwrap();
-        let metadata = entry.metadata()?;
+    #[test]
+    fn parses_valid_torrent() {
+        let info = parse_torrent(&torrent_json(10, &["00"])).unwrap();
+        assert_eq!(info.piece_count(), 1);
+        assert!(info.files.file_type == FileType::FILE);
+    }
 
-        // file metadata
-        let filename = entry.file_name().into_string().unwrap();
-        let filetype = match metadata.is_dir() {
-            true => "directory",
-            false => "file",
-            _ => "none"
-        };
-        let filesize = metadata.len();
+    #[test]
+    fn rejects_zero_piece_length() {
+        assert!(matches!(parse_torrent(&torrent_json(0, &[])), Err(Error::Parse(_))));
+    }
 
-        //println!("{:?}", entry.path());
-    
-        if metadata.is_dir() {
-            let new_path = entry.path();
-            let new_path = new_path.to_str().unwrap();
-            let mut data = dir_to_json(new_path)?;
-            let data = data.as_object_mut().unwrap();
+    #[test]
+    fn recheck_finds_valid_pieces() {
+        let data = b"abcdefghij";
+        let pieces = [hash_piece(&data[..4]), "00".to_string(), hash_piece(&data[8..])];
+        let pieces: Vec<&str> = pieces.iter().map(|p| p.as_str()).collect();
+        let info = parse_torrent(&torrent_json(4, &pieces)).unwrap();
 
-            data.insert("type".to_string(), json!("directory"));
+        let root = std::env::temp_dir().join(format!("baconnet-recheck-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let storage = Storage::new(&root, &info).unwrap();
+        storage.allocate().unwrap();
+        storage.write_at(0, data).unwrap();
 
-            let data = serde_json::to_value(data)?;
+        let valid = recheck_pieces(&info, &storage);
+        assert!(valid.get(0) && !valid.get(1) && valid.get(2));
 
-            return_data.insert(filename.clone(), data);
-        }
-        else {
-            let hash = get_file_hash(&format!("{:?}", entry.path()));
-
-            return_data.insert(filename, json!({
-                "type": filetype,
-                "size": filesize,
-                "hash": hash 
-            }));
-        }
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rejects_wrong_piece_hash_count() {
+        assert!(matches!(parse_torrent(&torrent_json(4, &["00"])), Err(Error::Parse(_))));
+        assert!(matches!(parse_torrent(&torrent_json(10, &[])), Err(Error::Parse(_))));
     }
 
-    let return_data = serde_json::to_value(return_data)?;
-    Ok(return_data)
+    #[test]
+    fn hashes_every_field_length_prefixed() {
+        let info: TorrentInfo = serde_json::from_value(serde_json::json!({
+            "filename": "dir",
+            "created_on": "0",
+            "size": 3,
+            "peers": [],
+            "piece_length": 10,
+            "pieces": ["00"],
+            "files": {
+                "filename": "dir",
+                "file_type": "directory",
+                "size": 3,
+                "children": [{ "filename": "a", "file_type": "file", "size": 3, "hash": "ff" }]
+            }
+        })).unwrap();
+
+        let mut hasher = Sha1::new();
+        hasher.update(3u64.to_be_bytes());
+        hasher.update(b"dir");
+        hasher.update(3u64.to_be_bytes());
+        hasher.update(10u64.to_be_bytes());
+        hasher.update(1u64.to_be_bytes());
+        hasher.update(2u64.to_be_bytes());
+        hasher.update(b"00");
+        // dir
+        hasher.update(3u64.to_be_bytes());
+        hasher.update(b"dir");
+        hasher.update([2]);
+        hasher.update(3u64.to_be_bytes());
+        hasher.update(0u64.to_be_bytes());
+        hasher.update(1u64.to_be_bytes());
+        // dir/a
+        hasher.update(1u64.to_be_bytes());
+        hasher.update(b"a");
+        hasher.update([1]);
+        hasher.update(3u64.to_be_bytes());
+        hasher.update(2u64.to_be_bytes());
+        hasher.update(b"ff");
+        hasher.update(0u64.to_be_bytes());
+
+        assert_eq!(info_hash(&info), format!("{:x}", hasher.finalize()));
+
+        // Moving bytes between fields changes the hash
+        let mut moved = info.clone();
+        moved.files.children[0].filename = "af".to_string();
+        moved.files.children[0].hash = "f".to_string();
+        assert_ne!(info_hash(&moved), info_hash(&info));
+
+        let mut changed = info.clone();
+        changed.files.children[0].hash = "00".to_string();
+        assert_ne!(info_hash(&changed), info_hash(&info));
+    }
 }
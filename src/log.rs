@@ -0,0 +1,84 @@
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::file::config::{LogConfig, LogLevel};
+
+// Anything less important than this is dropped
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static FILE: OnceLock<Mutex<File>> = OnceLock::new();
+// Keeps stdout clean for machine readable output
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn init(config: &LogConfig) -> std::io::Result<()> {
+    LEVEL.store(config.level as u8, Ordering::Relaxed);
+
+    if let Some(path) = &config.file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let _ = FILE.set(Mutex::new(file));
+    }
+
+    Ok(())
+}
+
+pub fn use_stderr() {
+    STDERR.store(true, Ordering::Relaxed);
+}
+
+// Use the macros below rather than calling this directly
+pub fn write(level: LogLevel, args: fmt::Arguments) {
+    if level as u8 > LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let line = args.to_string();
+    if STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    }
+    else {
+        println!("{}", line);
+    }
+
+    // The log file gets a timestamp since it
+    // outlives any single run
+    if let Some(file) = FILE.get() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if let Ok(mut file) = file.lock() {
+            let _ = writeln!(file, "{} {}", now, line);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::file::config::LogLevel::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::file::config::LogLevel::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::file::config::LogLevel::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::write($crate::file::config::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
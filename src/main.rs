@@ -1,352 +1,942 @@
-#![allow(warnings)]
-
-use std::fs::{self, File};
-use std::path::Path;
-use std::io::prelude::*;
-use std::thread;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use serde_json::json;
+use futures::StreamExt;
 use tokio::net::{
     TcpStream, 
     TcpListener,
 };
 use tokio::sync::mpsc::{channel, Sender, Receiver};
+use tokio::sync::oneshot;
 use tokio::net::UdpSocket;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
 
+mod cli;
 mod core;
+mod error;
 mod file;
+mod log;
+use crate::cli::{Cli, Command, EXIT_FAILURE};
 use crate::core::structs::{
     Packet, PacketType, 
     TorrentInfo, PieceRequest
 };
+use crate::core::bitfield::Bitfield;
+use crate::core::codec::{PacketCodec, decode_datagram, MAX_DATAGRAM_LEN};
+use crate::core::address::peer_addr;
+use crate::core::rate::RateLimit;
+use crate::core::pool::{forward, ConnectionPool, InboundConnection, PeerQueues};
+use crate::core::control::{self, Control, TorrentStatus};
+use crate::core::dht::{self, Dht};
+use crate::core::lsd;
+use crate::core::metadata;
+use crate::core::tracker;
+use crate::core::handshake::{self, TorrentSet};
 use crate::core::receive::*;
 use crate::core::send::*;
-use crate::file::torrent::{self};
-
-const TCP_PORT: u16 = 8080;
-const UDP_PORT: u16 = 8081;
+use crate::file::config::{Config, NetworkConfig, TorrentConfig};
+use crate::file::magnet::{is_magnet, metadata_path, save_metadata, MagnetLink};
 
 // This is arbitrary for now
 const CHANNEL_LIMIT: usize = 32;
 
+// How long to wait before trying to listen or accept again
+const BIND_RETRY: Duration = Duration::from_secs(1);
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+// How often a supervisor that's stopping checks
+// whether all of its threads are done
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+// Global rate limits, shared by every torrent
+#[derive(Clone)]
+struct Limits {
+    upload: RateLimit,
+    download: RateLimit,
+}
+
+impl Limits {
+    fn new(config: &Config) -> Self {
+        Self {
+            upload: RateLimit::new(config.limits.max_upload_rate),
+            download: RateLimit::new(config.limits.max_download_rate),
+        }
+    }
+
+    // The global limits plus whatever the torrent sets itself
+    fn for_torrent(&self, entry: &TorrentConfig) -> Self {
+        Self {
+            upload: self.upload.and(&RateLimit::new(entry.max_upload_rate.unwrap_or(0))),
+            download: self.download.and(&RateLimit::new(entry.max_download_rate.unwrap_or(0))),
+        }
+    }
+}
+
+// Sent once for every download, when it
+// finishes or can't be started at all
+struct DownloadResult {
+    torrent: String,
+    complete: bool,
+}
+
+// A running seed or download thread, as
+// seen by its supervisor
+struct TorrentHandle {
+    torrent: String,
+    sender: Sender<Packet>,
+    paused: Arc<AtomicBool>,
+    have: Arc<RwLock<Bitfield>>,
+    task: JoinHandle<()>,
+}
+
+impl TorrentHandle {
+    fn status(&self, hash: &str, kind: &str) -> TorrentStatus {
+        let have = self.have.read().unwrap();
+        TorrentStatus {
+            info_hash: hash.to_string(),
+            torrent: self.torrent.clone(),
+            kind: kind.to_string(),
+            paused: self.paused.load(Ordering::Relaxed),
+            have: have.count(),
+            pieces: have.len(),
+        }
+    }
+}
+
+// What a supervisor leaves behind once it stops
+struct Stopped {
+    // Its torrents as they should be saved in the config,
+    // including any added or removed while running
+    entries: Vec<TorrentConfig>,
+    statuses: Vec<TorrentStatus>,
+}
+
+// Once shutting down, supervisors keep routing packets
+// until every thread has finished what it was doing
+fn all_stopped(handles: &HashMap<String, TorrentHandle>) -> bool {
+    handles.values().all(|h| h.task.is_finished())
+}
+
+// Everything but adding a torrent works the same for
+// seeds and downloads. Adds are handed back to the caller.
+fn handle_control(
+    control: Control,
+    kind: &str,
+    handles: &mut HashMap<String, TorrentHandle>,
+    entries: &mut Vec<TorrentConfig>,
+    torrents: &TorrentSet
+) -> Option<(TorrentConfig, oneshot::Sender<Result<String, String>>)> {
+    match control {
+        Control::Add(entry, reply) => return Some((entry, reply)),
+        Control::Remove(hash, reply) => {
+            // Dropping its channel ends the thread
+            let handle = handles.remove(&hash);
+            if let Some(handle) = &handle {
+                entries.retain(|e| e.torrent != handle.torrent);
+                torrents.write().unwrap().remove(&hash);
+                info!("[CONTROL] Removed {} {}", kind, hash);
+            }
+            let _ = reply.send(handle.is_some());
+        },
+        Control::Pause(hash, reply) => {
+            let handle = handles.get(&hash);
+            if let Some(handle) = handle {
+                handle.paused.store(true, Ordering::Relaxed);
+                info!("[CONTROL] Paused {} {}", kind, hash);
+            }
+            let _ = reply.send(handle.is_some());
+        },
+        Control::Resume(hash, reply) => {
+            let handle = handles.get(&hash);
+            if let Some(handle) = handle {
+                handle.paused.store(false, Ordering::Relaxed);
+                info!("[CONTROL] Resumed {} {}", kind, hash);
+            }
+            let _ = reply.send(handle.is_some());
+        },
+        Control::List(reply) => {
+            let statuses = handles.iter()
+                .map(|(hash, handle)| handle.status(hash, kind))
+                .collect();
+            let _ = reply.send(statuses);
+        },
+    }
+
+    None
+}
+
+// Spawn the task serving a single torrent and
+// return the handle used to feed it packets
+fn spawn_seed_thread(
+    mut thread: SeedThread,
+    torrent: String,
+    udp: Arc<UdpSocket>,
+    m_sender: Sender<Packet>,
+    shutdown: CancellationToken
+) -> TorrentHandle {
+    let (sender, mut receiver) = channel(CHANNEL_LIMIT);
+    let paused = thread.pause_flag();
+    let have = thread.have();
+
+    let name = torrent.clone();
+    let task = tokio::spawn(async move {
+        if let Err(e) = thread.run(&mut receiver, &m_sender, &udp, &shutdown).await {
+            error!("[SEED] Stopped seeding {}: {}", name, e);
+        }
+    });
+
+    TorrentHandle {
+        torrent,
+        sender,
+        paused,
+        have,
+        task,
+    }
+}
+
+// Start seeding a torrent from the config or the
+// control socket. Returns its info hash.
+#[allow(clippy::too_many_arguments)]
+fn start_seed(
+    entry: &TorrentConfig,
+    config: &Config,
+    limits: &Limits,
+    udp: &Arc<UdpSocket>,
+    m_sender: &Sender<Packet>,
+    handles: &mut HashMap<String, TorrentHandle>,
+    torrents: &TorrentSet,
+    shutdown: &CancellationToken
+) -> Result<String, String> {
+    // Create thread object
+    let root = config.download_dir(entry);
+    let upload = limits.for_torrent(entry).upload;
+    let thread = SeedThread::new(&entry.torrent, &root, upload, config.network.tcp_port)
+        .map_err(|e| e.to_string())?;
+
+    // The same torrent listed twice
+    let hash = thread.info_hash().to_string();
+    if handles.contains_key(&hash) {
+        return Err(format!("Already seeding {}", hash));
+    }
+
+    // Spawn thread for torrent
+    torrents.write().unwrap().insert(hash.clone());
+    let handle = spawn_seed_thread(thread, entry.torrent.clone(), 
+        udp.clone(), m_sender.clone(), shutdown.clone());
+    handles.insert(hash.clone(), handle);
+
+    Ok(hash)
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn seed(
-    files: Vec<String>, 
+    config: Arc<Config>,
+    limits: Limits,
     udp: Arc<UdpSocket>, 
     m_sender: Sender<Packet>, 
-    m_receiver: &mut Receiver<Packet>
-) {
-    let mut comm_channels: HashMap<u64, Sender<Packet>> = HashMap::new();
-    let mut id_count: u64 = 0;
+    m_receiver: &mut Receiver<Packet>,
+    partial_receiver: &mut Receiver<PartialSeed>,
+    control: &mut Receiver<Control>,
+    torrents: TorrentSet,
+    shutdown: CancellationToken
+) -> Stopped {
+    // Seed threads keyed by info hash
+    let mut handles: HashMap<String, TorrentHandle> = HashMap::new();
+    let mut entries: Vec<TorrentConfig> = config.uploads.clone();
 
     // Spawn seed thread for each file
     // in the config
-    for file in files {
-        // Create thread object
-        let mut thread: SeedThread = match SeedThread::new(id_count, file.as_str()) {
-            Ok(t) => t,
-            Err(_) => {
-                // TODO try something else here
-                continue;
-            }
-        };
-
-        // Create channel
-        let (sender, mut receiver) = channel(CHANNEL_LIMIT);
-        comm_channels.insert(id_count, sender);
-        id_count += 1;
-       
-        // Spawn thread for torrent
-        let udp_clone = udp.clone();
-        tokio::spawn(async move {
-            loop {
-                let pieces: Vec<PieceRequest> = thread.get_assignments(&mut receiver).await;
-                for piece in pieces {
-                    thread.send_piece(piece, &udp_clone).await;
-                }
-            }    
-        });
+    for entry in &config.uploads {
+        let result = start_seed(entry, &config, &limits, &udp, &m_sender, 
+            &mut handles, &torrents, &shutdown);
+        if let Err(e) = result {
+            error!("[SEED] Can't seed {}: {}", entry.torrent, e);
+        }
     }
 
     // Redirect packets from manager to each
     // individual seed thread
-    loop {
-        if let Ok(packet) = m_receiver.try_recv() {
-            let id = packet.thread_id;
-            match comm_channels.get(&id) {
-                Some(thread) => {
-                    thread.send(packet).await.unwrap();
+    let mut stopping = false;
+    while !(stopping && all_stopped(&handles)) {
+        tokio::select! {
+            Some(packet) = m_receiver.recv() => {
+                // Nothing we're seeding
+                let handle = match handles.get(&packet.info_hash) {
+                    Some(h) => h,
+                    None => continue,
+                };
+
+                // Stopped threads stop listening, and one
+                // that can't keep up misses the packet
+                let _ = forward(&handle.sender, packet);
+            },
+            // Torrents still being downloaded can
+            // serve the pieces they have so far
+            Some(partial) = partial_receiver.recv(), if !stopping => {
+                let torrent = partial.info.filename.clone();
+                let thread = match SeedThread::from_partial(partial) {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+
+                // Already seeding the whole thing
+                let hash = thread.info_hash().to_string();
+                if handles.contains_key(&hash) {
+                    continue;
+                }
+
+                torrents.write().unwrap().insert(hash.clone());
+                let handle = spawn_seed_thread(thread, torrent, 
+                    udp.clone(), m_sender.clone(), shutdown.clone());
+                handles.insert(hash, handle);
+            },
+            // Torrents added or changed while running
+            Some(request) = control.recv(), if !stopping => {
+                let (entry, reply) = match handle_control(
+                    request, "seed", &mut handles, &mut entries, &torrents
+                ) {
+                    Some(add) => add,
+                    None => continue,
+                };
+
+                let result = start_seed(&entry, &config, &limits, &udp, &m_sender, 
+                    &mut handles, &torrents, &shutdown);
+                if result.is_ok() {
+                    info!("[CONTROL] Seeding {}", entry.torrent);
+                    entries.push(entry);
                 }
-                None => ()
+                let _ = reply.send(result);
+            },
+            _ = shutdown.cancelled(), if !stopping => stopping = true,
+            _ = sleep(STOP_POLL), if stopping => (),
+        }
+    }
+
+    Stopped {
+        entries,
+        statuses: handles.iter().map(|(hash, h)| h.status(hash, "seed")).collect(),
+    }
+}
+
+// A download either has its torrent already, or
+// has to fetch it from peers before it can start
+enum PendingDownload {
+    Ready(Box<DownloadThread>),
+    Magnet(MagnetLink),
+}
+
+// Start downloading a torrent from the config or the control
+// socket. Returns its info hash. Peers are found on the new
+// thread, so a slow peer never holds up the other torrents.
+#[allow(clippy::too_many_arguments)]
+async fn start_download(
+    entry: &TorrentConfig,
+    config: &Config,
+    limits: &Limits,
+    udp: &Arc<UdpSocket>,
+    m_sender: &Sender<Packet>,
+    partial_sender: &Sender<PartialSeed>,
+    done: &Sender<DownloadResult>,
+    handles: &mut HashMap<String, TorrentHandle>,
+    torrents: &TorrentSet,
+    shutdown: &CancellationToken
+) -> Result<String, String> {
+    let torrent_limits = limits.for_torrent(entry);
+    let root = config.download_dir(entry);
+    let max_peers = config.max_peers(entry);
+    let port = config.network.tcp_port;
+
+    // Magnet links only need fetching once, after that
+    // the torrent is kept next to the download
+    let pending = if is_magnet(&entry.torrent) {
+        let link = MagnetLink::parse(&entry.torrent).map_err(|e| e.to_string())?;
+        let saved = metadata_path(&root, &link.info_hash);
+        if saved.exists() {
+            let thread = DownloadThread::new(&saved.to_string_lossy(), &root, max_peers, 
+                torrent_limits.download.clone(), port)
+                .map_err(|e| e.to_string())?;
+            if thread.info_hash() != link.info_hash {
+                return Err(format!("{} doesn't match the magnet link", saved.display()));
             }
+            PendingDownload::Ready(Box::new(thread))
         }
+        else {
+            PendingDownload::Magnet(link)
+        }
+    }
+    else {
+        let thread = DownloadThread::new(&entry.torrent, &root, max_peers, 
+            torrent_limits.download.clone(), port)
+            .map_err(|e| e.to_string())?;
+        PendingDownload::Ready(Box::new(thread))
+    };
+
+    // The same torrent listed twice
+    let hash = match &pending {
+        PendingDownload::Ready(thread) => thread.info_hash().to_string(),
+        PendingDownload::Magnet(link) => link.info_hash.clone(),
+    };
+    if handles.contains_key(&hash) {
+        return Err(format!("Already downloading {}", hash));
     }
+
+    // Peers can only connect to us about torrents we know
+    // about, and the DHT and LAN look for peers for them
+    torrents.write().unwrap().insert(hash.clone());
+
+    // Create channel
+    let (sender, mut receiver) = channel(CHANNEL_LIMIT);
+    let paused = Arc::new(AtomicBool::new(false));
+    let have = Arc::new(RwLock::new(Bitfield::new(0)));
+
+    // Async thread to write data to disk
+    let sender_clone = m_sender.clone();
+    let partial_sender = partial_sender.clone();
+    let udp_clone = udp.clone();
+    let done = done.clone();
+    let torrent = entry.torrent.clone();
+    let shutdown = shutdown.clone();
+    let paused_clone = paused.clone();
+    let have_clone = have.clone();
+    let task = tokio::spawn(async move {
+        let result: crate::error::Result<bool> = async {
+            let mut thread = match pending {
+                PendingDownload::Ready(thread) => *thread,
+                PendingDownload::Magnet(link) => {
+                    let info = metadata::fetch(&link, &sender_clone, &mut receiver, &shutdown).await?;
+                    let saved = save_metadata(&root, &info)?;
+                    DownloadThread::new(&saved.to_string_lossy(), &root, max_peers, 
+                        torrent_limits.download, port)?
+                },
+            };
+            thread.share_state(&have_clone, &paused_clone);
+
+            // Serve pieces to others as they arrive
+            let _ = partial_sender.send(thread.partial_seed(torrent_limits.upload)).await;
+
+            // Find peers who have any pieces of the file. Pieces
+            // are requested from them as their answers come in.
+            thread.notify_peers(&sender_clone).await?;
+
+            thread.receive(&mut receiver, &sender_clone, &udp_clone, &shutdown).await
+        }.await;
+
+        // Only this torrent stops if something goes wrong
+        let complete = match result {
+            Ok(c) => c,
+            Err(e) => {
+                error!("[DOWNLOAD] Stopped downloading {}: {}", torrent, e);
+                false
+            }
+        };
+        if complete {
+            info!("[DOWNLOAD] Finished {}", torrent);
+        }
+
+        // Nobody is listening unless we're only downloading
+        let _ = done.try_send(DownloadResult { torrent, complete });
+    });
+
+    handles.insert(hash.clone(), TorrentHandle {
+        torrent: entry.torrent.clone(),
+        sender,
+        paused,
+        have,
+        task,
+    });
+
+    Ok(hash)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn download(
-    files: Vec<String>, 
+    config: Arc<Config>,
+    limits: Limits,
     udp: Arc<UdpSocket>, 
     m_sender: Sender<Packet>, 
-    m_receiver: &mut Receiver<Packet>
-) {
-    let mut comm_channels: HashMap<u64, Sender<Packet>> = HashMap::new();
-    let mut id_count: u64 = 0;
+    m_receiver: &mut Receiver<Packet>,
+    partial_sender: Sender<PartialSeed>,
+    done: Sender<DownloadResult>,
+    control: &mut Receiver<Control>,
+    torrents: TorrentSet,
+    shutdown: CancellationToken
+) -> Stopped {
+    // Download threads keyed by info hash
+    let mut handles: HashMap<String, TorrentHandle> = HashMap::new();
+    let mut entries: Vec<TorrentConfig> = config.downloads.clone();
 
     // Spawn download thread for each 
     // file stashed in the config file
-    for file in files {
-        let mut thread: DownloadThread = match DownloadThread::new(id_count, file.as_str()) {
-            Ok(t) => t,
-            Err(_) => {
-                // TODO try something else here
-                continue;
-            }
-        };
+    for entry in &config.downloads {
+        let result = start_download(entry, &config, &limits, &udp, &m_sender,
+            &partial_sender, &done, &mut handles, &torrents, &shutdown).await;
 
-        // Find peers who are actively seeding the file 
-        let valid_peers: Vec<String> = thread.notify_peers(&m_sender, m_receiver).await;
-        
-        // Request pieces from valid peers
-        thread.assign_pieces(valid_peers, &m_sender).await;
-       
-        // Create channel
-        let (sender, mut receiver) = channel(CHANNEL_LIMIT);
-        comm_channels.insert(id_count, sender);
-        id_count += 1;
-
-        // Async thread to write data to disk
-        tokio::spawn(async move {
-            thread.receive(&mut receiver).await;
-        });
+        if let Err(e) = result {
+            error!("[DOWNLOAD] Can't download {}: {}", entry.torrent, e);
+            let _ = done.try_send(DownloadResult {
+                torrent: entry.torrent.clone(),
+                complete: false,
+            });
+        }
     }
 
-    loop {
-        let mut buf: Vec<u8> = Vec::new();
-        match udp.recv(&mut buf).await {
-            Ok(_) => {
-                let data: String = String::from_utf8(buf).unwrap();
-                let packet: Packet = serde_json::from_str(data.as_str()).unwrap();
-                
-                let id = packet.thread_id;
-                let sender = match comm_channels.get(&id) {
-                    Some(s) => s,
-                    None => {
-                        // TODO we should do something smart here eventually.
-                        // For the time being we will just ignore this.
-                        // I.E. request that threads send their IDs out
-                        // to their peers again.
-                        continue;
-                    }
+    // Redirect packets from manager to each individual
+    // download thread, and take torrents added or
+    // changed while running
+    let mut stopping = false;
+    while !(stopping && all_stopped(&handles)) {
+        tokio::select! {
+            Some(packet) = m_receiver.recv() => {
+                // Nothing we're downloading
+                let handle = match handles.get(&packet.info_hash) {
+                    Some(h) => h,
+                    None => continue,
+                };
+
+                // Finished downloads stop listening, and one
+                // that can't keep up misses the packet
+                let _ = forward(&handle.sender, packet);
+            },
+            Some(request) = control.recv(), if !stopping => {
+                let (entry, reply) = match handle_control(
+                    request, "download", &mut handles, &mut entries, &torrents
+                ) {
+                    Some(add) => add,
+                    None => continue,
                 };
-                sender.send(packet).await.unwrap();
+
+                let result = start_download(&entry, &config, &limits, &udp, &m_sender,
+                    &partial_sender, &done, &mut handles, &torrents, &shutdown).await;
+                if result.is_ok() {
+                    info!("[CONTROL] Downloading {}", entry.torrent);
+                    entries.push(entry);
+                }
+                let _ = reply.send(result);
             },
-            Err(_) => continue
+            _ = shutdown.cancelled(), if !stopping => stopping = true,
+            _ = sleep(STOP_POLL), if stopping => (),
         }
     }
 
+    Stopped {
+        entries,
+        statuses: handles.iter().map(|(hash, h)| h.status(hash, "download")).collect(),
+    }
 }
 
-// Takes TCP requests from manager and sends the packet
-// to the specified destination
-async fn tcp_out(mut receiver: Receiver<Packet>) {
+// Listens for datagrams over UDP and hands them to
+// the download or seed side depending on their type.
+// Announces and scrapes go to the tracker, if we are one.
+async fn udp_in(
+    udp: Arc<UdpSocket>,
+    download_send: Sender<Packet>,
+    seed_send: Sender<Packet>,
+    tracker: Option<Sender<Packet>>,
+    dht: Option<Sender<Packet>>
+) {
+    let mut buf: Vec<u8> = vec![0; MAX_DATAGRAM_LEN];
     loop {
-        // Wait for request from manager
-        match receiver.recv().await {
-            Some(packet) => {
-                let tcp: TcpStream = loop {
-                    // Try to connect until successful
-                    // TODO eventually this should fail. How do
-                    // we handle said failure?
-                    match TcpStream::connect(&packet.dest_ip).await {
-                        Ok(s) => break s,
-                        Err(_) => continue,
-                    }
-                };
-                
-                // Convert packet to bytes
-                let data = serde_json::to_string(&packet).unwrap();
-                let bytes = data.as_bytes();
-                
-                // Try to send until successful
-                // TODO same as above
-                loop {
-                    match tcp.try_write(bytes) {
-                        Ok(_) => break,
-                        Err(_) => continue,
-                    }
+        let (len, addr) = match udp.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+
+        // Anything we can't decode is just dropped
+        let mut packet: Packet = match decode_datagram(&buf[..len]) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+        packet.from_ip = peer_addr(addr.ip(), addr.port());
+
+        // Either side is only gone when shutting down,
+        // and then the packet isn't needed anymore
+        match packet.packet_type {
+            PacketType::Block => {
+                let _ = download_send.send(packet).await;
+            },
+            PacketType::BlockAck => {
+                let _ = seed_send.send(packet).await;
+            },
+            // Downloads want the peers, and seeds
+            // need to know when to announce again
+            PacketType::AnnounceReply => {
+                let _ = seed_send.send(packet.clone()).await;
+                let _ = download_send.send(packet).await;
+            },
+            // Dropped rather than holding up blocks
+            // if the tracker can't keep up
+            PacketType::Announce | PacketType::Scrape => {
+                if let Some(tracker) = &tracker {
+                    let _ = tracker.try_send(packet);
+                }
+            },
+            PacketType::Dht => {
+                if let Some(dht) = &dht {
+                    let _ = dht.try_send(packet);
                 }
             },
-            None => continue
+            _ => (),
         }
     }
 }
 
+// Takes TCP requests from manager and sends the packet
+// to the specified destination
+async fn tcp_out(
+    mut receiver: Receiver<Packet>,
+    inbound: Sender<Packet>,
+    port: u16,
+    peers: PeerQueues
+) {
+    // Connections are kept open and reused per peer.
+    // Replies read from them go back through inbound.
+    let mut pool = ConnectionPool::new(inbound, port, peers);
+
+    // Wait for request from manager
+    while let Some(packet) = receiver.recv().await {
+        pool.send(packet);
+    }
+
+    // Manager is shutting down
+    pool.close().await;
+}
+
 // Listens for packets over TCP and redirects
 // them to manager
-async fn tcp_in(sender: Sender<Packet>) {
-    // Bind socket to port
+async fn tcp_in(
+    sender: Sender<Packet>,
+    torrents: TorrentSet,
+    network: NetworkConfig,
+    peers: PeerQueues,
+    close: CancellationToken
+) {
+    // Bind socket to port, waiting for it
+    // to be free if something else has it
+    let addr = SocketAddr::new(network.listen_address, network.tcp_port);
     let tcp = loop {
-        match TcpListener::bind(format!("127.0.0.1:{}", TCP_PORT)).await {
+        match TcpListener::bind(addr).await {
             Ok(s) => break s,
-            Err(_) => continue,
+            Err(e) => {
+                warn!("[TCP] Can't listen on {}: {}", addr, e);
+                tokio::select! {
+                    _ = sleep(BIND_RETRY) => (),
+                    _ = close.cancelled() => return,
+                }
+            }
         }
     };
 
     loop {
-        // Accept connection
-        let (socket, addr): (TcpStream, SocketAddr) = loop {
-            match tcp.accept().await {
-                Ok(s) => break s,
-                Err(_) => continue,
-            };
+        // Accept connection. Errors here are usually running
+        // out of file descriptors, so give some a chance to close.
+        let (socket, addr): (TcpStream, SocketAddr) = tokio::select! {
+            accepted = tcp.accept() => match accepted {
+                Ok(s) => s,
+                Err(_) => {
+                    sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            },
+            _ = close.cancelled() => return,
         };
 
         // Spawn thread to handle connection
         let copy = sender.clone();
+        let torrents = torrents.clone();
+        let peers = peers.clone();
+        let close = close.clone();
         tokio::spawn(async move {
-            handle_connection(socket, addr, copy)
+            handle_connection(socket, addr, copy, torrents, network.tcp_port, peers, close).await
         });
     }
-
-    
 }
 
 // Handles individual connections from peers
-async fn handle_connection(socket: TcpStream, addr: SocketAddr, sender: Sender<Packet>) {
-    // Listen for packets
-    loop {
-        let mut buf: Vec<u8> = Vec::new();
-        match socket.try_read(&mut buf) {
-            Ok(_) => (),
-            Err(_) => continue,
+async fn handle_connection(
+    socket: TcpStream,
+    addr: SocketAddr,
+    sender: Sender<Packet>,
+    torrents: TorrentSet,
+    port: u16,
+    peers: PeerQueues,
+    close: CancellationToken
+) {
+    let mut frames = Framed::new(socket, PacketCodec);
+
+    // Nothing is accepted before the handshake, and
+    // everything after it is about the same torrent
+    let handshake = match handshake::accept(&mut frames, &torrents, port).await {
+        Ok(h) => h,
+        Err(e) => {
+            warn!("[TCP] Rejected {}: {}", addr, e);
+            return;
         }
+    };
+    let info_hash = handshake.info_hash;
 
-        // Convert bytes to packet
-        let data = String::from_utf8(buf).unwrap();
-        let mut packet: Packet = serde_json::from_str(&data).unwrap();
-        packet.from_ip = format!("{}:{}", addr.ip(), addr.port());
+    // Packets are marked as coming from the address the
+    // peer listens on, so replies can go straight back
+    let from = peer_addr(addr.ip(), handshake.port);
 
-        // Redirect packet back to manager
-        // If this fails, then the program should crash anyways.
-        sender.send(packet).await.expect("Failed to send packet.");
+    // Replies to the peer go back over this connection
+    let (write, mut read) = frames.split();
+    let connection = InboundConnection::adopt(
+        &peers,
+        from.clone(),
+        info_hash.clone(),
+        write,
+        handshake.extensions
+    );
+
+    // Listen for packets until either side hangs up
+    loop {
+        let frame = tokio::select! {
+            frame = read.next() => match frame {
+                Some(f) => f,
+                None => break,
+            },
+            _ = close.cancelled() => break,
+        };
+
+        // A framing error means the stream can no
+        // longer be trusted, so drop the connection
+        let mut packet: Packet = match frame {
+            Ok(p) => p,
+            Err(_) => break,
+        };
+
+        // Only our own connection pool gets to say a peer is
+        // unreachable, the handshake is done, and nothing about
+        // other torrents is accepted on this connection
+        if packet.packet_type == PacketType::PeerUnreachable
+            || packet.packet_type == PacketType::Handshake
+            || packet.info_hash != info_hash
+        {
+            continue;
+        }
+        packet.from_ip = from.clone();
+
+        // Redirect packet back to manager,
+        // unless it has already shut down
+        if sender.send(packet).await.is_err() {
+            break;
+        }
     }
+
+    connection.close().await;
+}
+
+// Packet types only ever sent by download threads.
+// content is the tag of the undelivered packet.
+fn is_download_packet(content: &[u8]) -> bool {
+    let packet_type = match content.first() {
+        Some(tag) => PacketType::try_from(*tag),
+        None => return false,
+    };
+
+    matches!(
+        packet_type,
+        Ok(PacketType::FileCheck)
+        | Ok(PacketType::PieceRequest)
+        | Ok(PacketType::RequestDone)
+        | Ok(PacketType::Have)
+        | Ok(PacketType::MetadataRequest)
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn manager(
     receiver: &mut Receiver<Packet>, 
+    udp: Arc<UdpSocket>,
     download_send: Sender<Packet>,  
-    seed_send: Sender<Packet>) 
+    seed_send: Sender<Packet>,
+    torrents: TorrentSet,
+    network: NetworkConfig,
+    tracker: Option<Sender<Packet>>,
+    dht: Option<Sender<Packet>>,
+    close: CancellationToken) 
 {
     // Create TCP in and out processes
     let (in_send, mut in_recv) = channel(CHANNEL_LIMIT);
     let (out_send, out_recv) = channel(CHANNEL_LIMIT);
-    tokio::spawn(async move {
-        tcp_in(in_send)
+    let out_inbound = in_send.clone();
+    let in_close = close.clone();
+    let peers: PeerQueues = Arc::default();
+    let in_peers = peers.clone();
+    let tcp_in_thread = tokio::spawn(async move {
+        tcp_in(in_send, torrents, network, in_peers, in_close).await
+    });
+    let tcp_out_thread = tokio::spawn(async move {
+        tcp_out(out_recv, out_inbound, network.tcp_port, peers).await
     });
+
+    // UDP carries piece blocks and their acks
+    let udp_download = download_send.clone();
+    let udp_seed = seed_send.clone();
     tokio::spawn(async move {
-        tcp_out(out_recv)
+        udp_in(udp, udp_download, udp_seed, tracker, dht).await
     });
 
+    // Sleeps until either side has something, so
+    // an idle node doesn't use any CPU
     loop {
-        // Check for messages from other threads
-        
-        // From Seed/Download threads
-        match receiver.try_recv() {
-            Ok(packet) => {
+        tokio::select! {
+            // From Seed/Download threads. Never waits, since
+            // the threads may be waiting on us in turn.
+            Some(packet) = receiver.recv() => {
                 // Hand off to TCP outgoing
-                out_send.send(packet).await.unwrap();
+                if forward(&out_send, packet).is_err() {
+                    break;
+                }
             },
-            _ => ()
-        }
-
-        // TCP packet from peer
-        match in_recv.try_recv() {
-            Ok(packet) => {
+            // TCP packet from peer
+            // Seed and download stop before we do, anything
+            // arriving for them after that is dropped
+            Some(packet) = in_recv.recv() => {
                 match packet.packet_type {
-                    PacketType::PieceDelivery => {
-                        download_send.send(packet).await.unwrap();
+                    PacketType::PieceDelivery
+                    | PacketType::FileConfirm
+                    | PacketType::FileDeny
+                    | PacketType::Bitfield
+                    | PacketType::Have
+                    | PacketType::Pex
+                    | PacketType::MetadataReply => {
+                        let _ = forward(&download_send, packet);
+                    },
+                    // Goes back to whichever side sent the
+                    // packet that couldn't be delivered
+                    PacketType::PeerUnreachable if is_download_packet(&packet.content) => {
+                        let _ = forward(&download_send, packet);
                     },
                     _ => {
-                        seed_send.send(packet).await.unwrap();
+                        let _ = forward(&seed_send, packet);
                     },
                 }
-            }
-            _ => ()
+            },
+            // Seed and download have stopped by now
+            _ = close.cancelled() => break,
+            else => break,
         }
     }
+
+    // Closing our end lets tcp_out send whatever
+    // is left and hang up on every peer
+    drop(out_send);
+    let _ = tcp_out_thread.await;
+    let _ = tcp_in_thread.await;
 }
 
 #[tokio::main]
-async fn main() {
-    // Check if config file exists
-    let config_exists = Path::new("./config.json").exists();
-   
-    let mut downloads: Vec<String> = Vec::new();
-    let mut uploads: Vec<String> = Vec::new();
-
-    if config_exists {
-        println!("[MAIN] Reading config file");
-
-        // Open and read config file
-        let config = fs::File::open("./config.json")
-            .expect("Failed to open config file");
-        let json: serde_json::Value = serde_json::from_reader(config)
-            .expect("Failed to parse config file");
-
-        // Get list of files to download
-        downloads = match json.get("downloads") {
-            Some(arr) => {
-                let vec = arr.as_array().unwrap();
-                // Gross rust functional magic 
-                vec
-                    .iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect()
-            },
-            None => Vec::new()
-        };
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = match Cli::parse(&args) {
+        Ok(c) => c,
+        Err(e) => return cli::usage_error(&e),
+    };
 
-        // Get list of files to upload
-        uploads = match json.get("uploads") {
-            Some(arr) => {
-                let vec = arr.as_array().unwrap();
-                // Gross rust functional magic 
-                vec
-                    .iter()
-                    .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                    .collect()
-            },
-            None => Vec::new()
+    // These only deal with torrent files
+    match &cli.command {
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        },
+        Command::Create(path) => return cli::create(&cli, path),
+        Command::Verify(torrent, path) => return cli::verify(&cli, torrent, path),
+        Command::Info(torrent) => return cli::info(&cli, torrent),
+        Command::Scrape(torrent) => return cli::scrape(&cli, torrent).await,
+        _ => (),
+    }
+
+    let mut config = match Config::load(&cli.config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("[MAIN] Failed to load {}: {}", cli.config.display(), e);
+            return ExitCode::from(EXIT_FAILURE);
         }
+    };
+
+    // Command line flags win over the config file
+    if let Err(e) = config.network.apply_flags(&cli.network) {
+        return cli::usage_error(&e);
     }
-    else {
-        println!("[MAIN] Create config file");
 
-        // Create config file
-        let mut file = File::create("./config.json")
-            .expect("Failed to create config file");
+    // Torrents given on the command line replace the ones in the
+    // config, keeping any settings the config has for them
+    match &cli.command {
+        Command::Recheck(torrent) => return cli::recheck(&cli, &config, torrent),
+        Command::Ctl(args) => return cli::ctl(&cli, &config, args),
+        Command::Seed(torrents) => {
+            config.uploads = select_torrents(&config.uploads, torrents);
+            config.downloads.clear();
+            for entry in &mut config.uploads {
+                if cli.dir.is_some() {
+                    entry.download_dir = cli.dir.clone();
+                }
+            }
+        },
+        Command::Download(torrents) => {
+            config.downloads = select_torrents(&config.downloads, torrents);
+            config.uploads.clear();
+            for entry in &mut config.downloads {
+                if cli.dir.is_some() {
+                    entry.download_dir = cli.dir.clone();
+                }
+            }
+        },
+        _ => (),
+    }
 
-        // Empty arrays
-        let data = json!({
-            "downloads": [],
-            "uploads": [],
-        });
-        
-        // Write data to file
-        let json_str: String = serde_json::to_string_pretty(&data)
-            .expect("Failed to serialize config");
-        file.write(json_str.as_bytes())
-            .expect("Failed to write to file");
+    if cli.json {
+        log::use_stderr();
     }
+    if let Err(e) = log::init(&config.logging) {
+        eprintln!("[MAIN] Failed to open log file: {}", e);
+        return ExitCode::from(EXIT_FAILURE);
+    }
+
+    run_node(&cli, config).await
+}
+
+fn select_torrents(entries: &[TorrentConfig], torrents: &[String]) -> Vec<TorrentConfig> {
+    torrents.iter()
+        .map(|torrent| {
+            entries.iter()
+                .find(|e| &e.torrent == torrent)
+                .cloned()
+                .unwrap_or(TorrentConfig {
+                    torrent: torrent.clone(),
+                    ..Default::default()
+                })
+        })
+        .collect()
+}
+
+// Start seeding and downloading. Only returns on its
+// own when running download, once everything is done.
+async fn run_node(cli: &Cli, config: Config) -> ExitCode {
+    let network = config.network;
+    info!("[MAIN] Listening on {} (TCP {}, UDP {})", 
+        network.listen_address, network.tcp_port, network.udp_port);
 
     // Create UDP Socket 
-    let udp: Arc<UdpSocket> = Arc::new(
-        UdpSocket::bind(format!("127.0.0.1:{}", UDP_PORT))
-            .await
-            .expect("Failed to bind UDP socket")
-    );
+    let udp: Arc<UdpSocket> = match UdpSocket::bind(
+        SocketAddr::new(network.listen_address, network.udp_port)
+    ).await {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            error!("[MAIN] Failed to bind UDP socket: {}", e);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
 
     // Create channels for interthread communication
     
@@ -356,58 +946,278 @@ async fn main() {
     let (download_send, mut download_recv): (Sender<Packet>, Receiver<Packet>) = channel(CHANNEL_LIMIT);
     // Manager communication with Seed
     let (seed_send, mut seed_recv): (Sender<Packet>, Receiver<Packet>) = channel(CHANNEL_LIMIT);
+    // Download handing partial torrents to Seed
+    let (partial_send, mut partial_recv): (Sender<PartialSeed>, Receiver<PartialSeed>) = channel(CHANNEL_LIMIT);
+    // Download reporting finished torrents, one result each
+    let (done_send, mut done_recv): (Sender<DownloadResult>, Receiver<DownloadResult>) = 
+        channel(config.downloads.len() + CHANNEL_LIMIT);
+    // Control socket asking Download and Seed to change torrents
+    let (download_control, mut download_control_recv): (Sender<Control>, Receiver<Control>) = channel(CHANNEL_LIMIT);
+    let (seed_control, mut seed_control_recv): (Sender<Control>, Receiver<Control>) = channel(CHANNEL_LIMIT);
 
-    println!("Seeding:");
-    for entry in &uploads {
-        println!("{}", entry);
-    }
-    println!("Downloading:");
-    for entry in &downloads {
-        println!("{}", entry);
+    if !cli.json {
+        println!("Seeding:");
+        for entry in &config.uploads {
+            println!("{}", entry.torrent);
+        }
+        println!("Downloading:");
+        for entry in &config.downloads {
+            println!("{}", entry.torrent);
+        }
     }
 
+    let config = Arc::new(config);
+    let limits = Limits::new(&config);
+
+    // Info hashes of everything we seed or download
+    let torrents: TorrentSet = Arc::new(RwLock::new(HashSet::new()));
+
+    // Cancelled first to let torrents wind down,
+    // then to close connections once they have
+    let shutdown = CancellationToken::new();
+    let close = CancellationToken::new();
+
     // Setup seed thread
     let udp_clone = udp.clone();
     let sender_clone = sender.clone();
+    let torrents_clone = torrents.clone();
+    let config_clone = config.clone();
+    let limits_clone = limits.clone();
+    let shutdown_clone = shutdown.clone();
     let seed_thread = tokio::spawn(async move {
-        println!("[MAIN] Spawning seed thread");
-        seed(uploads, udp_clone, sender_clone, 
-            &mut seed_recv).await;
+        info!("[MAIN] Spawning seed thread");
+        seed(config_clone, limits_clone, udp_clone, sender_clone, &mut seed_recv, 
+            &mut partial_recv, &mut seed_control_recv, torrents_clone, shutdown_clone).await
     });
 
     // Setup download thread
     let udp_clone = udp.clone();
     let sender_clone = sender.clone();
+    let torrents_clone = torrents.clone();
+    let config_clone = config.clone();
+    let shutdown_clone = shutdown.clone();
     let download_thread = tokio::spawn(async move {
-        println!("[MAIN] Spawning download thread");
-        download(downloads, udp_clone, sender_clone, &mut download_recv).await;
+        info!("[MAIN] Spawning download thread");
+        download(config_clone, limits, udp_clone, sender_clone, 
+            &mut download_recv, partial_send, done_send, &mut download_control_recv, 
+            torrents_clone, shutdown_clone).await
     });
 
+    // Only the tracker command answers announces
+    let mut tracker_send: Option<Sender<Packet>> = None;
+    let mut tracker_thread = None;
+    if matches!(cli.command, Command::Tracker) {
+        let (send, mut recv) = channel(CHANNEL_LIMIT);
+        let udp_clone = udp.clone();
+        let interval = Duration::from_secs(config.tracker.interval);
+        let shutdown_clone = shutdown.clone();
+        tracker_thread = Some(tokio::spawn(async move {
+            tracker::serve(&mut recv, udp_clone, interval, shutdown_clone).await
+        }));
+        tracker_send = Some(send);
+    }
+
+    // Finds peers for our torrents without a tracker
+    let mut dht_send: Option<Sender<Packet>> = None;
+    let mut dht_thread = None;
+    if config.dht.enabled {
+        let (send, mut recv) = channel(CHANNEL_LIMIT);
+        let dht = Dht::new(udp.clone(), network.tcp_port, torrents.clone(), download_send.clone());
+        let bootstrap = config.dht.bootstrap.clone();
+        let shutdown_clone = shutdown.clone();
+        dht_thread = Some(tokio::spawn(async move {
+            dht::run(dht, &mut recv, bootstrap, shutdown_clone).await
+        }));
+        dht_send = Some(send);
+    }
+
+    // Finds peers on the LAN
+    let lsd_thread = match config.lsd.enabled {
+        true => {
+            let interface = config.lsd.interface;
+            let torrents_clone = torrents.clone();
+            let found = download_send.clone();
+            let shutdown_clone = shutdown.clone();
+            Some(tokio::spawn(async move {
+                lsd::run(interface, network.tcp_port, torrents_clone, found, shutdown_clone).await
+            }))
+        },
+        false => None,
+    };
+
     // Wait for messages over TCP and
     // messages from seed and download threads
+    let close_clone = close.clone();
     let manager_thread = tokio::spawn(async move {
-        println!("[MAIN] Spawning manager thread");
-        manager(&mut receiver, download_send, seed_send).await;
+        info!("[MAIN] Spawning manager thread");
+        manager(&mut receiver, udp, download_send, seed_send, 
+            torrents, network, tracker_send, dht_send, close_clone).await;
     });
 
-    println!("[MAIN] Program running");
-    
+    // Lets torrents be added and changed while running
+    let control_thread = match config.control.enabled {
+        true => {
+            let path = PathBuf::from(&config.control.socket);
+            let shutdown_clone = shutdown.clone();
+            Some(tokio::spawn(async move {
+                control::serve(&path, download_control, seed_control, shutdown_clone).await
+            }))
+        },
+        false => None,
+    };
+
+    info!("[MAIN] Program running");
 
-    let _ = seed_thread.await;
-    let _ = download_thread.await;
+    // Run until told to stop. Only downloading also stops
+    // once every download has reported, including the
+    // ones that couldn't be started.
+    let only_downloading = matches!(cli.command, Command::Download(_));
+    let mut results: Vec<DownloadResult> = Vec::new();
+    tokio::select! {
+        _ = shutdown_signal() => info!("[MAIN] Shutting down, press Ctrl-C again to stop now"),
+        _ = wait_for_downloads(&mut done_recv, &mut results, config.downloads.len()), 
+            if only_downloading => (),
+    }
+
+    // Nobody wants to wait twice
+    tokio::spawn(async {
+        shutdown_signal().await;
+        std::process::exit(EXIT_FAILURE as i32);
+    });
+
+    // Seed and download finish whatever is in flight,
+    // then the manager hangs up on every peer
+    shutdown.cancel();
+    let seeds = seed_thread.await;
+    let downloads = download_thread.await;
+    close.cancel();
     let _ = manager_thread.await;
+    if let Some(control_thread) = control_thread {
+        let _ = control_thread.await;
+    }
+    if let Some(tracker_thread) = tracker_thread {
+        let _ = tracker_thread.await;
+    }
+    if let Some(dht_thread) = dht_thread {
+        let _ = dht_thread.await;
+    }
+    if let Some(lsd_thread) = lsd_thread {
+        let _ = lsd_thread.await;
+    }
 
-    println!("[MAIN] Exiting...");
+    info!("[MAIN] Exiting...");
 
-    /*
-    torrent::create_torrent_file("./files/test").expect("Failed to create JSON file");
+    if only_downloading {
+        while let Ok(result) = done_recv.try_recv() {
+            results.push(result);
+        }
+        return download_summary(cli, &results, config.downloads.len());
+    }
 
-    let tree = torrent::parse_torrent_file("./torrents/test.json")
-        .expect("Failed to parse torrent file");
-    
-    //torrent::print_tree(&tree, 0);
+    let (seeds, downloads) = match (seeds, downloads) {
+        (Ok(s), Ok(d)) => (s, d),
+        _ => return ExitCode::from(EXIT_FAILURE),
+    };
 
-    let result = torrent::verify_file_tree(&tree, "./files/test");
-    println!("{}", result);
-    */
+    // Torrents given on the command line
+    // aren't meant to be kept
+    if matches!(cli.command, Command::Daemon | Command::Tracker) {
+        save_torrents(&cli.config, &downloads.entries, &seeds.entries);
+    }
+
+    let mut statuses = downloads.statuses;
+    for status in seeds.statuses {
+        if !statuses.iter().any(|s| s.info_hash == status.info_hash) {
+            statuses.push(status);
+        }
+    }
+    shutdown_summary(cli, &statuses);
+
+    ExitCode::SUCCESS
+}
+
+// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("[MAIN] Can't listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+async fn wait_for_downloads(
+    done: &mut Receiver<DownloadResult>,
+    results: &mut Vec<DownloadResult>,
+    count: usize
+) {
+    while results.len() < count {
+        match done.recv().await {
+            Some(result) => results.push(result),
+            None => break,
+        }
+    }
+}
+
+// Write torrents added or removed while running back to the
+// config. The file is read again so that flags given on the
+// command line don't end up saved in it.
+fn save_torrents(path: &Path, downloads: &[TorrentConfig], uploads: &[TorrentConfig]) {
+    let mut config = match Config::load(path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("[MAIN] Failed to save torrents to {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if config.downloads == downloads && config.uploads == uploads {
+        return;
+    }
+
+    config.downloads = downloads.to_vec();
+    config.uploads = uploads.to_vec();
+    match config.save(path) {
+        Ok(()) => info!("[MAIN] Saved torrents to {}", path.display()),
+        Err(e) => error!("[MAIN] Failed to save torrents to {}: {}", path.display(), e),
+    }
+}
+
+fn shutdown_summary(cli: &Cli, statuses: &[TorrentStatus]) {
+    if cli.json {
+        println!("{}", serde_json::json!({ "torrents": statuses }));
+        return;
+    }
+
+    println!("Stopped {} torrent(s)", statuses.len());
+    for status in statuses {
+        println!("{}: {}/{} pieces ({})", status.torrent, status.have, status.pieces, status.kind);
+    }
+}
+
+// Fails unless every download completed
+fn download_summary(cli: &Cli, results: &[DownloadResult], count: usize) -> ExitCode {
+    if cli.json {
+        let downloads: Vec<serde_json::Value> = results.iter()
+            .map(|r| serde_json::json!({ "torrent": r.torrent, "complete": r.complete }))
+            .collect();
+        println!("{}", serde_json::json!({ "downloads": downloads }));
+    }
+    else {
+        for result in results {
+            let status = if result.complete { "complete" } else { "failed" };
+            println!("{}: {}", result.torrent, status);
+        }
+    }
+
+    match results.len() >= count && results.iter().all(|r| r.complete) {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::from(EXIT_FAILURE),
+    }
 }